@@ -0,0 +1,164 @@
+//! An async front for `Bitcask`, behind the `tokio` feature. Every call runs
+//! the blocking operation through `spawn_blocking`, on tokio's bounded pool
+//! of blocking threads, so awaiting it never stalls the runtime's workers.
+
+use crate::bitcask::{Bitcask, BitcaskError};
+use std::{
+    panic,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::{task, time};
+
+/// A `Bitcask` shared behind a mutex, so calls from any number of tasks
+/// apply one at a time.
+#[derive(Debug, Clone)]
+pub struct AsyncBitcask {
+    inner: Arc<Mutex<Bitcask>>,
+    timeout: Option<Duration>,
+}
+
+impl AsyncBitcask {
+    pub fn new(inner: Bitcask) -> Self {
+        AsyncBitcask {
+            inner: Arc::new(Mutex::new(inner)),
+            timeout: None,
+        }
+    }
+
+    /// Fails `get`, `put` and `delete` with `Timeout` once they've taken
+    /// longer than `timeout`, waiting for the store included. The blocking
+    /// call can't be cancelled, so one that timed out still runs to the
+    /// end: a put or delete may yet be applied.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub async fn get(&self, key: Vec<u8>) -> Result<Vec<u8>, BitcaskError> {
+        self.call(move |bitcask| bitcask.get(&key)).await
+    }
+
+    /// Writes `value` under `key`, returning the value it replaced, if any.
+    pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, BitcaskError> {
+        self.call(move |bitcask| bitcask.put(key, value)).await
+    }
+
+    /// Deletes `key`, returning whether it had a live value.
+    pub async fn delete(&self, key: Vec<u8>) -> Result<bool, BitcaskError> {
+        self.call(move |bitcask| bitcask.delete(&key)).await
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T, BitcaskError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Bitcask) -> Result<T, BitcaskError> + Send + 'static,
+    {
+        match self.timeout {
+            Some(timeout) => time::timeout(timeout, self.run(f))
+                .await
+                .map_err(|_| BitcaskError::Timeout)?,
+            None => self.run(f).await,
+        }
+    }
+
+    /// Runs `f` against the store on a blocking thread, for everything the
+    /// methods above don't cover. A panic in `f` resumes in the caller.
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Bitcask) -> T + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        let call = task::spawn_blocking(move || {
+            // A call that panicked leaves the store as a failed write
+            // would, so later calls carry on with it.
+            let mut bitcask = inner.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut bitcask)
+        });
+        match call.await {
+            Ok(value) => value,
+            // Blocking tasks are never aborted, so this is a panic in `f`.
+            Err(e) => panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::fresh_dir;
+    use std::sync::mpsc;
+    use tokio::sync::oneshot;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_puts_and_gets() {
+        let path = fresh_dir("/tmp/async1");
+        let store = AsyncBitcask::new(Bitcask::open(path).expect("Failed to open Bitcask"));
+        let tasks: Vec<_> = (0..8u8)
+            .map(|task| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for i in 0..10u8 {
+                        let key = vec![task, i];
+                        store.put(key.clone(), vec![i; 10]).await.unwrap();
+                        assert_eq!(store.get(key).await.unwrap(), vec![i; 10]);
+                    }
+                    assert!(store.delete(vec![task, 0]).await.unwrap());
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(store.run(|bitcask| bitcask.len()).await, 8 * 9);
+        assert!(matches!(
+            store.get(vec![3, 0]).await,
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert_eq!(store.get(vec![3, 9]).await.unwrap(), vec![9; 10]);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let path = fresh_dir("/tmp/async2");
+        let store = AsyncBitcask::new(Bitcask::open(path).expect("Failed to open Bitcask"))
+            .with_timeout(Duration::from_millis(10));
+        // Stands in for a slow disk: the store is busy until the test lets
+        // it go.
+        let (busy_tx, busy_rx) = oneshot::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let busy = tokio::spawn({
+            let store = store.clone();
+            async move {
+                store
+                    .run(move |_| {
+                        busy_tx.send(()).unwrap();
+                        release_rx.recv().unwrap();
+                    })
+                    .await
+            }
+        });
+        busy_rx.await.unwrap();
+
+        assert!(matches!(
+            store.get(b"a".to_vec()).await,
+            Err(BitcaskError::Timeout)
+        ));
+        let (applied_tx, applied_rx) = oneshot::channel();
+        let late = store.call(move |bitcask| {
+            let old = bitcask.put(b"a".to_vec(), b"late".to_vec());
+            applied_tx.send(()).unwrap();
+            old
+        });
+        assert!(matches!(late.await, Err(BitcaskError::Timeout)));
+        release_tx.send(()).unwrap();
+        busy.await.unwrap();
+
+        // The put that timed out went ahead anyway.
+        applied_rx.await.unwrap();
+        let value = store.run(|bitcask| bitcask.get(b"a")).await;
+        assert_eq!(value.unwrap(), b"late");
+    }
+}
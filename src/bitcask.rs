@@ -1,25 +1,141 @@
+use crate::clock::{Clock, SystemClock};
+use crate::compression::{self, Compression};
+use crate::encryption::EncryptionConfig;
+use crate::flusher::Flusher;
 use std::{
-    collections::{HashMap, HashSet},
-    fs,
-    io::{Read, Seek, Write},
-    os::unix::fs::FileExt,
-    path::{self, Path, PathBuf},
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt, fs,
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
 };
 
-const TOMBSTONE: &[u8] = b"__TOMBSTONE__";
+// Version 1 files marked a delete by writing this as the value instead of
+// setting FLAG_DELETE.
+const LEGACY_TOMBSTONE: &[u8] = b"__TOMBSTONE__";
 
-#[derive(Debug)]
+// Suffix for merge output that hasn't been renamed into place yet. Anything
+// carrying it is left over from an interrupted merge.
+const MERGING_EXT: &str = "merging";
+
+// Rough size of a hint entry, for sizing the key dir up front: five u64
+// fields and a short key.
+const AVG_HINT_ENTRY_SIZE: u64 = 8 * 5 + 16;
+
+// Upper bound on data file handles kept open for reads.
+const MAX_READ_HANDLES: usize = 64;
+
+// Held exclusively by the single writer of a data directory.
+const LOCK_FILE: &str = "kuro.lock";
+
+// Holds what `set_meta` stores, apart from the data files so none of it
+// turns up among the keys or goes away in a merge.
+// FORMAT: MAGIC + VERSION, then KEY_SIZE + VALUE_SIZE + KEY + VALUE for each
+// entry
+const META_FILE: &str = "kuro.meta";
+const META_MAGIC: &[u8; 4] = b"KRMT";
+
+// Left behind by a writer that closed cleanly, and taken away again by the
+// next one to open.
+const CLEAN_FILE: &str = "kuro.clean";
+
+// FORMAT: CRC + TMSTMP + EXPIRY + FLAGS + KEY_SIZE + VALUE_SIZE
+const HEADER_SIZE: u64 = 8 + 8 + 8 + 1 + 8 + 8;
+const FLAGS_OFFSET: usize = 8 + 8 + 8;
+
+// Every data and hint file opens with a magic number and format version, so
+// files from an incompatible layout are refused instead of misparsed.
+// Version 3 added the hint trailer; its data files are the same as version 2.
+// FORMAT: MAGIC + VERSION
+const FILE_HEADER_SIZE: u64 = 4 + 4;
+const DATA_MAGIC: &[u8; 4] = b"KRDT";
+const HINT_MAGIC: &[u8; 4] = b"KRHT";
+const FORMAT_VERSION: u32 = 3;
+
+// Hint files end with the number of entries they hold and a CRC of those
+// entries, so a hint that was cut short or damaged gets noticed.
+// FORMAT: COUNT + CRC
+const HINT_TRAILER_SIZE: u64 = 8 + 8;
+
+// Record flags: how the stored value was compressed, and whether it was then
+// encrypted. An encrypted value is the nonce, the ciphertext and the tag. A
+// delete is a record with FLAG_DELETE and an empty value.
+const FLAG_LZ4: u8 = 1 << 0;
+const FLAG_SNAPPY: u8 = 1 << 1;
+const FLAG_ENCRYPTED: u8 = 1 << 2;
+const FLAG_DELETE: u8 = 1 << 3;
+const FLAG_PADDING: u8 = 1 << 4;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// CRC32 (IEEE) over the concatenation of `chunks`.
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    !chunks
+        .iter()
+        .fold(!0u32, |crc, chunk| crc32_update(crc, chunk))
+}
+
+// Feeds `bytes` into a running CRC, for checksums taken a piece at a time.
+// The running value starts at `!0` and is inverted once at the end.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct KeyDir {
     file_id: u64,
     value_size: u64,
     value_pos: u64,
     timestamp: u64,
+    // Milliseconds since the epoch after which the value is gone; 0 never
+    // expires.
+    expiry: u64,
+    tombstone: bool,
+}
+
+impl KeyDir {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expiry != 0 && now >= self.expiry
+    }
+
+    // Whether the entry still names a value a caller should see.
+    fn is_live(&self, now: u64) -> bool {
+        !self.tombstone && !self.is_expired(now)
+    }
 }
 
 #[derive(Debug)]
 struct HintFileEntry {
     timestamp: u64,
+    expiry: u64,
     key_size: u64,
     value_size: u64,
     value_pos: u64,
@@ -30,6 +146,7 @@ impl HintFileEntry {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.expiry.to_le_bytes());
         bytes.extend_from_slice(&self.key_size.to_le_bytes());
         bytes.extend_from_slice(&self.value_size.to_le_bytes());
         bytes.extend_from_slice(&self.value_pos.to_le_bytes());
@@ -38,422 +155,7356 @@ impl HintFileEntry {
     }
 }
 
+// Writes hint entries after a header that's already written, counting and
+// checksumming them for the trailer `finish` adds.
+struct HintWriter<W> {
+    file: W,
+    count: u64,
+    crc: u32,
+}
+
+impl<W: Write> HintWriter<W> {
+    fn new(file: W) -> Self {
+        HintWriter {
+            file,
+            count: 0,
+            crc: !0,
+        }
+    }
+
+    fn write(&mut self, entry: &HintFileEntry) -> io::Result<()> {
+        let bytes = entry.to_bytes();
+        self.file.write_all(&bytes)?;
+        self.count += 1;
+        self.crc = crc32_update(self.crc, &bytes);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.file.write_all(&self.count.to_le_bytes())?;
+        self.file.write_all(&(!self.crc as u64).to_le_bytes())?;
+        Ok(self.file)
+    }
+}
+
+// Splits the trailer off the entries of a hint file of format 3 or later,
+// returning the entries if it matches them.
+fn check_hint_trailer(entries: &[u8]) -> Option<(&[u8], u64)> {
+    let split = entries.len().checked_sub(HINT_TRAILER_SIZE as usize)?;
+    let (entries, trailer) = entries.split_at(split);
+    let count = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let crc = u64::from_le_bytes(trailer[8..].try_into().unwrap());
+    (crc32(&[entries]) as u64 == crc).then_some((entries, count))
+}
+
 #[derive(Debug)]
 pub struct Bitcask {
     key_dir: HashMap<Vec<u8>, KeyDir>,
-    active_file: fs::File,
+    // None on read-only handles, which never write to the directory.
+    // Reads of the active file share its descriptor rather than opening
+    // another.
+    active_file: Option<BufWriter<Arc<fs::File>>>,
     active_file_id: u64,
     writer_pos: u64,
     data_path: PathBuf,
+    max_file_size: u64,
+    lock_file: Option<fs::File>,
+    sync_policy: SyncPolicy,
+    unsynced_writes: u64,
+    // Running under `SyncPolicy::Interval` on writable handles.
+    flusher: Option<Flusher>,
+    auto_merge_threshold: f64,
+    total_bytes: u64,
+    dead_bytes: u64,
+    compression: Compression,
+    max_value_size: u64,
+    recovery_mode: RecoveryMode,
+    // Read handles by file id. Data files are append-only, so a handle stays
+    // valid until merge deletes its file.
+    read_handles: Mutex<HashMap<u64, Arc<fs::File>>>,
+    // Sorted mirror of the live keys in key_dir, kept when the ordered index
+    // is enabled.
+    ordered_keys: Option<BTreeSet<Vec<u8>>>,
+    write_buffer_size: usize,
+    encryption: Option<EncryptionConfig>,
+    layout: Layout,
+    drop_deleted_keys: bool,
+    hint_on_rotate: bool,
+    clean_shutdown: bool,
+    observer: Option<Observer>,
+    // Sealed files still waiting for `merge_step` this round, newest first.
+    merge_queue: Vec<u64>,
+    // Set in hashed key mode, which leaves `key_dir` empty.
+    hashed: Option<HashedKeyDir>,
+    counters: Counters,
+    record_alignment: u64,
+    meta: BTreeMap<String, Vec<u8>>,
+    keep_versions: usize,
+    clock: Arc<dyn Clock>,
+    max_keys: Option<usize>,
+    // Unreadable records skipped when the key dir was last built.
+    skipped_records: u64,
+    failed_auto_merges: u64,
+    // The latest auto-merge failure, until `take_auto_merge_error`.
+    auto_merge_error: Option<BitcaskError>,
 }
 
+// With `hashed_keys`, the key dir keeps a 64-bit hash of each key instead of
+// the key itself, and keys are checked against the records on disk. A key
+// whose hash another key already holds is kept in full in `collided`.
 #[derive(Debug)]
-struct DataFileEntry {
-    crc: u64,
-    timestamp: u64,
-    key_size: u64,
-    value_size: u64,
-    key: Vec<u8>,
-    value: Vec<u8>,
+struct HashedKeyDir {
+    hasher: fn(&[u8]) -> u64,
+    slots: HashMap<u64, HashedEntry>,
+    collided: HashMap<Vec<u8>, KeyDir>,
 }
 
-impl DataFileEntry {
-    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
-        let crc = 0;
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        let key_size = key.len() as u64;
-        let value_size = value.len() as u64;
-        DataFileEntry {
-            crc, // TODO: Calculate CRC
-            timestamp,
-            key_size,
-            value_size,
-            key,
-            value,
+#[derive(Debug)]
+struct HashedEntry {
+    kd: KeyDir,
+    // The key sits just ahead of the value, so this is enough to find it.
+    key_size: u32,
+}
+
+impl HashedKeyDir {
+    fn new() -> Self {
+        HashedKeyDir {
+            hasher: fnv1a,
+            slots: HashMap::new(),
+            collided: HashMap::new(),
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.crc.to_le_bytes());
-        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
-        bytes.extend_from_slice(&self.key_size.to_le_bytes());
-        bytes.extend_from_slice(&self.value_size.to_le_bytes());
-        bytes.extend_from_slice(&self.key);
-        bytes.extend_from_slice(&self.value);
-        bytes
+    // Replaces the contents with `key_dir`. Every key sharing a hash goes to
+    // `collided`, so this needs no disk reads.
+    fn fill(&mut self, key_dir: HashMap<Vec<u8>, KeyDir>) {
+        let mut counts: HashMap<u64, u32> = HashMap::with_capacity(key_dir.len());
+        for key in key_dir.keys() {
+            *counts.entry((self.hasher)(key)).or_default() += 1;
+        }
+        self.clear();
+        self.slots.reserve(key_dir.len());
+        for (key, kd) in key_dir {
+            let hash = (self.hasher)(&key);
+            if counts[&hash] > 1 {
+                self.collided.insert(key, kd);
+            } else {
+                let key_size = key.len() as u32;
+                self.slots.insert(hash, HashedEntry { kd, key_size });
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.slots = HashMap::new();
+        self.collided = HashMap::new();
     }
 }
 
-#[derive(Debug)]
-pub enum BitcaskError {
-    Io(std::io::Error),
-    InvalidFileFormat,
-    KeyNotFound,
-    DirNotFound,
+// FNV-1a, the key hash for `hashed_keys`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
-impl From<std::io::Error> for BitcaskError {
-    fn from(error: std::io::Error) -> Self {
-        BitcaskError::Io(error)
+/// A write as reported to the observer set with `set_observer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+// The callback behind a mutex only so the store stays `Sync`. It's only ever
+// called through `&mut self`, so the lock is never contended.
+struct Observer(Mutex<Box<dyn FnMut(Event) + Send>>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
     }
 }
 
-fn gen_file_id<P: AsRef<Path>>(dirpath: P) -> Result<u64, BitcaskError> {
-    let path: &Path = dirpath.as_ref();
-    fs::create_dir_all(path)?;
-    let entries = path.read_dir()?;
-    let mut max_id = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs();
-    entries.into_iter().for_each(|entry| {
-        if let Ok(entry) = entry {
-            if let Some(file_id) = get_file_id(&entry.path()) {
-                max_id = max_id.max(file_id);
-            }
+/// Controls when `put` fsyncs the active file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Only sync on rotation or an explicit `sync`.
+    Never,
+    /// Sync after every write.
+    EveryWrite,
+    /// Sync after every N writes.
+    EveryN(u64),
+    /// Sync every interval from a background thread. Each write goes out to
+    /// the OS at once, so a machine crash loses at most the last interval.
+    Interval(Duration),
+}
+
+/// What opening does with a record that fails its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Fail with `CorruptRecord`.
+    #[default]
+    Strict,
+    /// Skip the record and carry on from the next intact one.
+    Lenient,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Size in bytes after which the active data file is rotated.
+    pub max_file_size: u64,
+    pub sync_policy: SyncPolicy,
+    /// Dead-to-total byte ratio above which `put` runs a merge. Any value of
+    /// 1.0 or more disables automatic merging.
+    pub auto_merge_threshold: f64,
+    /// Codec for newly written values. Existing records keep whatever codec
+    /// they were written with.
+    pub compression: Compression,
+    /// Keep a sorted index of keys alongside the key dir so `range` doesn't
+    /// have to sort every key on each call.
+    pub ordered_index: bool,
+    /// Largest value `put` accepts, in bytes before compression.
+    pub max_value_size: u64,
+    pub recovery_mode: RecoveryMode,
+    /// Expected number of keys, used to size the key dir before loading it.
+    pub expected_keys: Option<usize>,
+    /// Bytes of writes held in memory before they go to the active file.
+    /// Reads still see buffered writes; `flush` and `sync` push them out. 0
+    /// writes every record straight through.
+    pub write_buffer_size: usize,
+    /// Encrypts values written from now on. Reading an encrypted value needs
+    /// the key it was written with.
+    pub encryption: Option<EncryptionConfig>,
+    /// Spreads data and hint files over this many subdirectories, by file
+    /// id, so no one directory grows huge. 0 keeps them all directly in the
+    /// data directory. A directory must always be opened with the same value.
+    pub shards: u32,
+    /// Take deleted keys out of the key dir as soon as their delete is
+    /// written, instead of keeping them until the next merge. The delete
+    /// record on disk still wins over earlier values when the store reopens.
+    pub drop_deleted_keys: bool,
+    /// Writes a hint file for each data file as the active file rotates out
+    /// of it, so reopening doesn't have to scan it.
+    pub hint_on_rotate: bool,
+    /// Keeps a 64-bit hash of each key in memory rather than the key, for
+    /// stores with huge numbers of keys. Lookups and overwrites then check
+    /// the key against its record on disk. Listing and scanning keys (`iter`,
+    /// `values`, `fold`, `list_keys`, `keys_page`, `keys_since`,
+    /// `prefix_scan`, `range`) see nothing in this mode and the ordered index
+    /// isn't kept. Opening and merging still hold every key for as long as
+    /// they run.
+    pub hashed_keys: bool,
+    /// Starts every record on a multiple of this many bytes, such as 512 to
+    /// keep records within sectors, padding the gap ahead of each one. 0
+    /// leaves records packed. Files written with any alignment read back
+    /// under any other.
+    pub record_alignment: u64,
+    /// Creates the directory if it doesn't exist yet. Without it, opening a
+    /// missing directory fails with `DirNotFound`, which catches mistyped
+    /// paths.
+    pub create_if_missing: bool,
+    /// Source of timestamps and TTL expiry. Swap in a `MockClock` to drive
+    /// time by hand in tests.
+    pub clock: Arc<dyn Clock>,
+    /// How many of each live key's most recent values a full merge keeps,
+    /// counting the current one. Older ones stay readable through
+    /// `scan_log`. A merge can't reclaim them, so they don't count as dead
+    /// bytes towards `auto_merge_threshold`.
+    /// `merge_step` only ever keeps the current value.
+    pub keep_versions: usize,
+    /// Most keys the key dir may hold. Writing a new key past it fails with
+    /// `Full`; overwriting one it holds still works. Deleted and expired
+    /// keys count until a merge clears them out, or straight away go with
+    /// `drop_deleted_keys`.
+    pub max_keys: Option<usize>,
+    /// Opens a handle for reading alongside a writer, as `open_read_only`
+    /// does.
+    pub read_only: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_file_size: 1024 * 1024 * 1024,
+            sync_policy: SyncPolicy::Never,
+            auto_merge_threshold: 1.0,
+            compression: Compression::None,
+            ordered_index: false,
+            max_value_size: u64::MAX,
+            recovery_mode: RecoveryMode::Strict,
+            expected_keys: None,
+            write_buffer_size: 0,
+            encryption: None,
+            shards: 0,
+            drop_deleted_keys: false,
+            hint_on_rotate: true,
+            hashed_keys: false,
+            record_alignment: 0,
+            create_if_missing: true,
+            keep_versions: 1,
+            clock: Arc::new(SystemClock),
+            max_keys: None,
+            read_only: false,
         }
-    });
-    Ok(max_id + 1)
+    }
 }
 
-fn get_file_id(filepath: &Path) -> Option<u64> {
-    filepath.file_stem()?.to_str()?.parse::<u64>().ok()
+impl Options {
+    /// Starts from the defaults; see `OptionsBuilder`.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
 }
 
-fn build_keydir<P: AsRef<Path>>(path: P) -> Result<HashMap<Vec<u8>, KeyDir>, BitcaskError> {
-    let dir: &Path = path.as_ref();
-    let mut map = HashMap::new();
-    if !dir.exists() {
-        println!("Directory does not exist: {:?}", dir);
-        return Err(BitcaskError::DirNotFound);
+/// Builds `Options` one setting at a time, leaving the rest at their
+/// defaults.
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.options.max_file_size = max_file_size;
+        self
     }
-    let entries = dir.to_path_buf().read_dir()?;
-    let mut sorted_entries = entries.into_iter().collect::<Result<Vec<_>, _>>()?;
-    sorted_entries.sort_by(|a, b| {
-        let a_id = get_file_id(&a.path());
-        let b_id = get_file_id(&b.path());
-        a_id.cmp(&b_id)
-    });
-    let mut processed: HashSet<u64> = HashSet::new();
-    for entry in sorted_entries {
-        let mut file_pos = 0;
-        if let Some(extension) = entry.path().extension() {
-            if extension != "dat" {
-                continue;
-            }
-        }
-        let file_id = match get_file_id(&entry.path()) {
-            Some(id) => id,
-            None => {
-                continue;
-            }
-        };
-        if processed.contains(&file_id) {
-            continue;
-        }
-        processed.insert(file_id);
-        let hint_filepath = entry.path().with_extension("hint");
-        if hint_filepath.exists() {
-            let mut hint_file = fs::File::open(&hint_filepath)?;
-            let file_len = hint_file.metadata()?.len();
-            let mut buf = [0u8; 8];
-
-            while file_pos < file_len {
-                // timestamp
-                let _ = hint_file.read_exact(&mut buf);
-                let timestamp = u64::from_le_bytes(buf);
-                file_pos += 8;
-
-                // key size
-                let _ = hint_file.read_exact(&mut buf);
-                let key_size = u64::from_le_bytes(buf);
-                file_pos += 8;
-
-                // value size
-                let _ = hint_file.read_exact(&mut buf);
-                let value_size = u64::from_le_bytes(buf);
-                file_pos += 8;
-
-                // value pos
-                let _ = hint_file.read_exact(&mut buf);
-                let value_pos = u64::from_le_bytes(buf);
-                file_pos += 8;
-
-                // key
-                let mut key = vec![0u8; key_size as usize];
-
-                let _ = hint_file.read_exact(&mut key);
-                file_pos += key_size;
-
-                let map_entry = KeyDir {
-                    file_id,
-                    value_size,
-                    value_pos,
-                    timestamp,
-                };
 
-                map.insert(key, map_entry);
-            }
-        } else {
-            let mut dat_file = fs::File::open(&entry.path())?;
-            let mut buf = [0u8; 8];
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.options.sync_policy = sync_policy;
+        self
+    }
 
-            let file_len = dat_file.metadata()?.len();
+    pub fn auto_merge_threshold(mut self, auto_merge_threshold: f64) -> Self {
+        self.options.auto_merge_threshold = auto_merge_threshold;
+        self
+    }
 
-            while file_pos < file_len {
-                // Skip CRC for now!
-                file_pos += 8;
-                let _ = dat_file.seek_relative(8);
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
 
-                let _ = dat_file.read_exact(&mut buf);
-                let timestamp = u64::from_le_bytes(buf);
-                file_pos += 8;
+    pub fn ordered_index(mut self, ordered_index: bool) -> Self {
+        self.options.ordered_index = ordered_index;
+        self
+    }
 
-                let _ = dat_file.read_exact(&mut buf);
-                let key_size = u64::from_le_bytes(buf);
-                file_pos += 8;
+    pub fn max_value_size(mut self, max_value_size: u64) -> Self {
+        self.options.max_value_size = max_value_size;
+        self
+    }
 
-                let _ = dat_file.read_exact(&mut buf);
-                let value_size = u64::from_le_bytes(buf);
-                file_pos += 8;
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.options.recovery_mode = recovery_mode;
+        self
+    }
 
-                let mut key = vec![0u8; key_size as usize];
-                let _ = dat_file.read_exact(&mut key)?;
-                file_pos += key_size;
+    pub fn expected_keys(mut self, expected_keys: usize) -> Self {
+        self.options.expected_keys = Some(expected_keys);
+        self
+    }
 
-                let map_entry = KeyDir {
-                    file_id,
-                    value_size,
-                    value_pos: file_pos,
-                    timestamp,
-                };
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.options.write_buffer_size = write_buffer_size;
+        self
+    }
 
-                let _ = dat_file.seek_relative(value_size as i64);
-                file_pos += value_size;
+    pub fn encryption(mut self, encryption: EncryptionConfig) -> Self {
+        self.options.encryption = Some(encryption);
+        self
+    }
 
-                map.insert(key, map_entry);
-            }
-        }
+    pub fn shards(mut self, shards: u32) -> Self {
+        self.options.shards = shards;
+        self
     }
-    Ok(map)
-}
 
-impl Bitcask {
-    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BitcaskError> {
-        let path: PathBuf = path.into();
-        let path: &Path = path.as_ref();
-        let file_id = gen_file_id(path)?;
-        if !path.exists() {
-            let _ = fs::create_dir(path)?;
-        }
-        let filepath = path.join(format!("{}.dat", file_id));
-        let active_file = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(filepath)?;
-        let key_dir = build_keydir(path)?;
-        Ok(Bitcask {
-            key_dir,
-            active_file,
-            active_file_id: file_id,
-            writer_pos: 0,
-            data_path: path.to_path_buf(),
-        })
+    pub fn drop_deleted_keys(mut self, drop_deleted_keys: bool) -> Self {
+        self.options.drop_deleted_keys = drop_deleted_keys;
+        self
     }
 
-    pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, BitcaskError> {
-        let kd_value = self.key_dir.get(key);
-        match kd_value {
-            Some(kd) => {
-                let dirpath: &Path = self.data_path.as_ref();
-                let filepath = dirpath.join(format!("{}.dat", kd.file_id));
-                let data_file = fs::File::open(filepath)?;
-                let mut buf = vec![0u8; kd.value_size as usize];
-                data_file.read_exact_at(&mut buf, kd.value_pos)?;
-                return Ok(buf);
-            }
-            None => Err(BitcaskError::KeyNotFound),
-        }
+    pub fn hint_on_rotate(mut self, hint_on_rotate: bool) -> Self {
+        self.options.hint_on_rotate = hint_on_rotate;
+        self
     }
 
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        let key_size = key.len() as u64;
-        let value_size = value.len() as u64;
-        let entry = DataFileEntry::new(key.to_vec(), value);
-        //  FORMAT: CRC + TMSTMP + KEY_SIZE + VALUE_SIZE + KEY
-        let value_pos = self.writer_pos + 8 + 8 + 8 + 8 + key_size;
-        let kd_value = KeyDir {
-            file_id: self.active_file_id,
-            value_size,
-            value_pos,
-            timestamp: entry.timestamp,
-        };
-        let data = entry.to_bytes();
-        let _ = self.active_file.write(&data);
-        // FORMAT: CRC + TMSTMP + KEY_SIZE + VALUE_SIZE + KEY + VALUE
-        self.writer_pos += data.len() as u64;
-        self.key_dir.insert(key, kd_value);
+    pub fn hashed_keys(mut self, hashed_keys: bool) -> Self {
+        self.options.hashed_keys = hashed_keys;
+        self
     }
 
-    pub fn delete(&mut self, key: Vec<u8>) {
-        self.put(key, TOMBSTONE.to_vec());
+    pub fn record_alignment(mut self, record_alignment: u64) -> Self {
+        self.options.record_alignment = record_alignment;
+        self
     }
 
-    pub fn list_keys(&self) -> Option<Vec<&Vec<u8>>> {
-        Some(self.key_dir.keys().collect::<Vec<&Vec<u8>>>())
-    }
-
-    pub fn merge<P: AsRef<Path>>(&mut self, dirpath: P) -> Result<(), BitcaskError> {
-        let keydir = build_keydir(&dirpath)?;
-        let mut file_id = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        let mut merge_filepath = path::Path::new(&self.data_path).join(format!("{}.dat", file_id));
-        if merge_filepath.exists() {
-            file_id += 1;
-        }
-        merge_filepath = path::Path::new(&self.data_path).join(format!("{}.dat", file_id));
-        let mut merge_file = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(merge_filepath)?;
-        let hint_filepath = path::Path::new(&self.data_path).join(format!("{}.hint", file_id));
-        let mut hint_file = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(hint_filepath)?;
-        let mut write_pos = 0;
-        let tombstone = TOMBSTONE.to_vec();
-        for (key, _) in &keydir {
-            if let Ok(value) = self.get(&key) {
-                if value.eq(&tombstone) {
-                    continue;
-                }
-                let key_len = key.len() as u64;
-                let entry = DataFileEntry::new(key.to_vec(), value);
-                let data = entry.to_bytes();
-                let _ = merge_file.write(&data);
-                let value_pos = write_pos + 8 + 8 + 8 + 8 + key_len;
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.options.create_if_missing = create_if_missing;
+        self
+    }
 
-                let hint_entry = HintFileEntry {
-                    timestamp: entry.timestamp,
-                    key_size: entry.key_size,
-                    value_size: entry.value_size,
-                    value_pos,
-                    key: entry.key,
-                };
-                let _ = hint_file.write(&hint_entry.to_bytes());
+    pub fn keep_versions(mut self, keep_versions: usize) -> Self {
+        self.options.keep_versions = keep_versions;
+        self
+    }
 
-                write_pos += data.len() as u64;
-            }
-        }
-        let dirpath: &Path = dirpath.as_ref();
-        let dir = dirpath.read_dir()?;
-        for file in dir {
-            let filepath = file?.path();
-            let id = match get_file_id(&filepath) {
-                Some(id) => id,
-                None => {
-                    continue;
-                }
-            };
-            if id == file_id || id == self.active_file_id {
-                continue;
-            }
-            let _ = fs::remove_file(filepath);
-        }
-        merge_file.sync_all()?;
-        hint_file.sync_all()?;
-        self.active_file = merge_file;
-        self.active_file_id = file_id;
-        self.writer_pos = write_pos;
-        self.key_dir = keydir;
-        Ok(())
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.options.clock = Arc::new(clock);
+        self
     }
 
-    pub fn fold() {
-        panic!("Sync operation not implemented yet");
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.options.max_keys = Some(max_keys);
+        self
     }
 
-    pub fn sync(&mut self) -> Result<(), BitcaskError> {
-        self.active_file.sync_all()?;
-        return Ok(());
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
     }
 
-    pub fn close(self) {
-        drop(self.active_file);
+    pub fn build(self) -> Options {
+        self.options
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashSet;
+/// A value together with where and when it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub value: Vec<u8>,
+    /// Milliseconds since the epoch.
+    pub timestamp: u64,
+    pub file_id: u64,
+    pub value_pos: u64,
+}
 
-    use super::*;
+/// One physical record of the log, from `scan_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub key: Vec<u8>,
+    /// Empty for a delete.
+    pub value: Vec<u8>,
+    /// Milliseconds since the epoch.
+    pub timestamp: u64,
+    pub tombstone: bool,
+}
 
-    #[test]
-    fn test_get_put() {
-        let mut bitcask = Bitcask::open("/tmp/test1").expect("Failed to open Bitcask");
-        bitcask.put(b"key1".to_vec(), b"value1".to_vec());
-        let result = bitcask.get(&b"key1".to_vec()).expect("Failed to get value");
-        assert_eq!(result, b"value1".to_vec());
-    }
+/// Operation counts since the store was opened, from `metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Metrics {
+    /// Values written, counting each entry of a batch.
+    pub puts: u64,
+    /// Lookups, hits and misses alike, counting each key of a `get_many`.
+    pub gets: u64,
+    pub deletes: u64,
+    /// Reads served by an already open data file handle.
+    pub handle_hits: u64,
+    /// Reads that had to open their data file first.
+    pub handle_misses: u64,
+    /// Full merges, whether run directly, automatically or committed.
+    pub merges: u64,
+    /// Bytes appended to the active file.
+    pub bytes_written: u64,
+}
 
-    #[test]
-    fn test_list_keys() {
-        let bitcask = Bitcask::open("/tmp/test1").expect("Failed to open Bitcask");
-        let keys = bitcask.list_keys();
-        assert_eq!(keys, Some(vec![&b"key1".to_vec()]));
+// The live counters behind `Metrics`. Reads only take `&self`, so they're
+// atomic; nothing orders by them, so relaxed updates are enough.
+#[derive(Debug, Default)]
+struct Counters {
+    puts: AtomicU64,
+    gets: AtomicU64,
+    deletes: AtomicU64,
+    handle_hits: AtomicU64,
+    handle_misses: AtomicU64,
+    merges: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+fn bump(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Point-in-time figures for monitoring and deciding when to merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitcaskStats {
+    pub data_files: usize,
+    /// Bytes across all data files.
+    pub total_bytes: u64,
+    pub live_keys: usize,
+    pub tombstones: usize,
+    /// Bytes a merge run now would free.
+    pub reclaimable_bytes: u64,
+    /// Bytes of records since overwritten or deleted, kept up to date by
+    /// every write. This is what auto-merge goes by.
+    pub dead_bytes: u64,
+    /// Unreadable records `RecoveryMode::Lenient` passed over when the key
+    /// dir was last built, on open or `reload`.
+    pub skipped_records: u64,
+    /// Auto-merges that failed since the store was opened. The writes that
+    /// set them off went through regardless.
+    pub failed_auto_merges: u64,
+}
+
+/// Merge output from `prepare_merge`, not yet swapped in. Dropping it
+/// without `commit_merge` throws the output away.
+#[derive(Debug)]
+pub struct PreparedMerge {
+    dirpath: PathBuf,
+    layout: Layout,
+    file_ids: Vec<u64>,
+    key_dir: HashMap<Vec<u8>, KeyDir>,
+    // Where writes stood when the merge started.
+    active_file_id: u64,
+    writer_pos: u64,
+    published: bool,
+}
+
+impl PreparedMerge {
+    // Whether `kd` points at a record written after the merge started.
+    fn is_newer(&self, kd: &KeyDir) -> bool {
+        kd.file_id > self.active_file_id
+            || (kd.file_id == self.active_file_id && kd.value_pos > self.writer_pos)
     }
 
-    #[test]
-    fn test_build_keydir() {
-        // let mut bitcask = Bitcask::open("/tmp/test3");
-        // bitcask.put(b"key1".to_vec(), b"value1".to_vec());
-        // bitcask.put(b"key2".to_vec(), b"value2".to_vec());
+    fn temp_path(&self, file_id: u64, ext: &str) -> PathBuf {
+        self.layout
+            .path(&self.dirpath, file_id, &format!("{}.{}", ext, MERGING_EXT))
+    }
 
-        let key_dir = build_keydir("/tmp/test1").expect("Failed to build keydir");
-        assert_eq!(key_dir.len(), 1);
-        assert!(key_dir.contains_key(&b"key1".to_vec()));
+    // Renames the output into place. Each data file goes before its hint:
+    // on its own it is simply scanned.
+    fn publish(&mut self) -> io::Result<()> {
+        for &file_id in &self.file_ids {
+            for ext in ["dat", "hint"] {
+                let filepath = self.layout.path(&self.dirpath, file_id, ext);
+                fs::rename(self.temp_path(file_id, ext), filepath)?;
+            }
+        }
+        self.published = true;
+        for dir in self.layout.dirs(&self.dirpath) {
+            sync_dir(&dir)?;
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_keydir() {
-        let bitcask = Bitcask::open("/tmp/test1").expect("Failed to open Bitcask");
-        let result = bitcask.get(&b"key1".to_vec()).expect("Failed to get value");
-        assert_eq!(result, b"value1".to_vec());
+impl Drop for PreparedMerge {
+    fn drop(&mut self) {
+        if self.published {
+            return;
+        }
+        for &file_id in &self.file_ids {
+            for ext in ["dat", "hint"] {
+                let _ = fs::remove_file(self.temp_path(file_id, ext));
+            }
+        }
     }
+}
 
-    #[test]
-    fn test_merge() {
-        let mut bitcask = Bitcask::open("/tmp/test4").expect("Failed to open Bitcask");
-        bitcask.put(b"key1".to_vec(), b"value1".to_vec());
-        bitcask.put(b"key2".to_vec(), b"value2".to_vec());
+// What `value_reader` hands out: the value streamed from its data file, or
+// one that had to be decoded whole.
+enum ValueReader {
+    File {
+        data: io::Take<fs::File>,
+        crc: u32,
+        stored_crc: u64,
+    },
+    Decoded(io::Cursor<Vec<u8>>),
+}
 
-        let mut bitcask2 = Bitcask::open("/tmp/test4").expect("Failed to open Bitcask");
-        let _ = bitcask2.merge("/tmp/test4");
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (data, crc, stored_crc) = match self {
+            ValueReader::File {
+                data,
+                crc,
+                stored_crc,
+            } => (data, crc, *stored_crc),
+            ValueReader::Decoded(value) => return value.read(buf),
+        };
+        let n = data.read(buf)?;
+        *crc = crc32_update(*crc, &buf[..n]);
+        if n == 0 && !buf.is_empty() {
+            if data.limit() > 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if !*crc as u64 != stored_crc {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "value failed its CRC check",
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
 
-        let bitcask3 = Bitcask::open("/tmp/test4").expect("Failed to open Bitcask");
-        let val1 = bitcask3
-            .get(&b"key1".to_vec())
-            .expect("Failed to get value");
-        let val2 = bitcask3
-            .get(&b"key2".to_vec())
-            .expect("Failed to get value");
+/// What `merge_dry_run` expects a merge to do.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MergePlan {
+    /// Data files the merge would delete, in id order.
+    pub files_removed: Vec<u64>,
+    /// Live records it would copy into new files, counting older versions
+    /// kept by `keep_versions`.
+    pub live_records: u64,
+    /// Data file bytes it would free, going by the sizes records have now.
+    /// Values are encoded afresh as they're copied, so a change of
+    /// compression can move the real figure.
+    pub reclaimed_bytes: u64,
+}
 
-        let mut files = HashSet::new();
+/// What `verify` found across the data files.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    pub total_records: u64,
+    /// `(file_id, offset)` of every record that fails its checksum or runs
+    /// past the end of its file, in file order.
+    pub corrupt_records: Vec<(u64, u64)>,
+}
 
-        bitcask3.key_dir.iter().for_each(|(_, v)| {
-            files.insert(v.file_id);
-        });
+#[derive(Debug)]
+struct DataFileEntry {
+    crc: u64,
+    timestamp: u64,
+    expiry: u64,
+    flags: u8,
+    key_size: u64,
+    value_size: u64,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl DataFileEntry {
+    // `timestamp` is when the record was first written, which a rewrite
+    // keeps.
+    pub fn with_timestamp(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        timestamp: u64,
+        expiry: u64,
+        flags: u8,
+    ) -> Self {
+        let key_size = key.len() as u64;
+        let value_size = value.len() as u64;
+        let crc = Self::checksum(timestamp, expiry, flags, key_size, value_size, &key, &value);
+        DataFileEntry {
+            crc,
+            timestamp,
+            expiry,
+            flags,
+            key_size,
+            value_size,
+            key,
+            value,
+        }
+    }
+
+    // CRC over TMSTMP + EXPIRY + FLAGS + KEY_SIZE + VALUE_SIZE + KEY + VALUE
+    pub fn checksum(
+        timestamp: u64,
+        expiry: u64,
+        flags: u8,
+        key_size: u64,
+        value_size: u64,
+        key: &[u8],
+        value: &[u8],
+    ) -> u64 {
+        crc32(&[
+            &timestamp.to_le_bytes(),
+            &expiry.to_le_bytes(),
+            &[flags],
+            &key_size.to_le_bytes(),
+            &value_size.to_le_bytes(),
+            key,
+            value,
+        ]) as u64
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        record_bytes(
+            self.crc,
+            self.timestamp,
+            self.expiry,
+            self.flags,
+            &self.key,
+            &self.value,
+        )
+    }
+}
+
+// Serializes a record into a buffer allocated once at its exact size.
+fn record_bytes(
+    crc: u64,
+    timestamp: u64,
+    expiry: u64,
+    flags: u8,
+    key: &[u8],
+    value: &[u8],
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE as usize + key.len() + value.len());
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes.extend_from_slice(&timestamp.to_le_bytes());
+    bytes.extend_from_slice(&expiry.to_le_bytes());
+    bytes.push(flags);
+    bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+// With `record_alignment` set, every record starts on a multiple of it. The
+// gap before one is filled by a padding record: FLAG_PADDING, no key, and a
+// zeroed value making up the rest of the gap. Scans step over it like any
+// other record, and its checksum covers the length it gives. A gap too short
+// to hold a record header is widened by another `alignment`.
+fn padding_len(alignment: u64, pos: u64) -> u64 {
+    if alignment <= 1 || pos.is_multiple_of(alignment) {
+        return 0;
+    }
+    let mut len = alignment - pos % alignment;
+    while len < HEADER_SIZE {
+        len += alignment;
+    }
+    len
+}
+
+fn padding_record(len: u64) -> Vec<u8> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let value = vec![0u8; (len - HEADER_SIZE) as usize];
+    let crc = DataFileEntry::checksum(0, 0, FLAG_PADDING, 0, value.len() as u64, &[], &value);
+    record_bytes(crc, 0, 0, FLAG_PADDING, &[], &value)
+}
+
+#[derive(Debug)]
+pub enum BitcaskError {
+    Io(std::io::Error),
+    InvalidFileFormat,
+    KeyNotFound,
+    DirNotFound,
+    AlreadyLocked,
+    ActiveFile,
+    ReadOnly,
+    CorruptRecord {
+        file_id: u64,
+        value_pos: u64,
+    },
+    ValueTooLarge,
+    Decode,
+    UnsupportedVersion(u32),
+    /// An encrypted value failed authentication: the wrong key, no key, or
+    /// tampered bytes.
+    Decryption,
+    /// The call needs the keys themselves, which a store opened with
+    /// `hashed_keys` doesn't keep.
+    HashedKeys,
+    /// Keys can't be empty.
+    InvalidKey,
+    /// A new key would take the store past `max_keys`.
+    Full,
+    /// An async call didn't finish within its timeout.
+    Timeout,
+    /// Data files with this id turned up in more than one shard directory.
+    DuplicateFileId(u64),
+}
+
+impl fmt::Display for BitcaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // The io error itself comes through `source`.
+            BitcaskError::Io(_) => f.write_str("I/O error"),
+            BitcaskError::InvalidFileFormat => f.write_str("invalid file format"),
+            BitcaskError::KeyNotFound => f.write_str("key not found"),
+            BitcaskError::DirNotFound => f.write_str("directory not found"),
+            BitcaskError::AlreadyLocked => f.write_str("directory is locked by another writer"),
+            BitcaskError::ActiveFile => f.write_str("operation not allowed on the active file"),
+            BitcaskError::ReadOnly => f.write_str("store is read-only"),
+            BitcaskError::CorruptRecord { file_id, value_pos } => write!(
+                f,
+                "corrupt record in data file {} at value position {}",
+                file_id, value_pos
+            ),
+            BitcaskError::ValueTooLarge => f.write_str("value too large"),
+            BitcaskError::Decode => f.write_str("value failed to decode"),
+            BitcaskError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            BitcaskError::Decryption => f.write_str("value failed to decrypt"),
+            BitcaskError::HashedKeys => f.write_str("not supported with hashed keys"),
+            BitcaskError::InvalidKey => f.write_str("invalid key"),
+            BitcaskError::Full => f.write_str("store is full"),
+            BitcaskError::Timeout => f.write_str("operation timed out"),
+            BitcaskError::DuplicateFileId(file_id) => {
+                write!(f, "more than one data file with id {}", file_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BitcaskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BitcaskError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BitcaskError {
+    fn from(error: std::io::Error) -> Self {
+        BitcaskError::Io(error)
+    }
+}
+
+// Compresses `value` with `compression`, returning the record flags that say
+// how. Values that don't shrink are stored as they are.
+fn encode_value(compression: Compression, value: Vec<u8>) -> (u8, Vec<u8>) {
+    let (flag, compressed) = match compression {
+        Compression::None => return (0, value),
+        Compression::Lz4 => (FLAG_LZ4, compression::lz4_compress(&value)),
+        Compression::Snappy => (FLAG_SNAPPY, compression::snappy_compress(&value)),
+    };
+    if compressed.len() < value.len() {
+        (flag, compressed)
+    } else {
+        (0, value)
+    }
+}
+
+fn decode_value(flags: u8, value: Vec<u8>) -> Option<Vec<u8>> {
+    if flags & FLAG_LZ4 != 0 {
+        compression::lz4_decompress(&value)
+    } else if flags & FLAG_SNAPPY != 0 {
+        compression::snappy_decompress(&value)
+    } else {
+        Some(value)
+    }
+}
+
+// Last file id handed out by this process, so ids keep increasing even when
+// several are generated before any of their files exist.
+static LAST_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+// New ids start from the time `now`, so they sort in creation order.
+fn gen_file_id<P: AsRef<Path>>(layout: Layout, dirpath: P, now: u64) -> Result<u64, BitcaskError> {
+    let path: &Path = dirpath.as_ref();
+    let entries = layout.entries(path)?;
+    let mut max_id = now;
+    entries.into_iter().for_each(|entry| {
+        if let Some(file_id) = store_file_id(&entry) {
+            max_id = max_id.max(file_id);
+        }
+    });
+    let last = LAST_FILE_ID
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(last.max(max_id) + 1)
+        })
+        .unwrap();
+    Ok(last.max(max_id) + 1)
+}
+
+// Positional read that leaves the file cursor alone where the platform allows.
+#[cfg(unix)]
+fn read_exact_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(mut file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+// Makes creations and deletions in the directory durable. Only unix lets a
+// directory be opened and synced like this.
+#[cfg(unix)]
+fn sync_dir(dirpath: &Path) -> io::Result<()> {
+    fs::File::open(dirpath)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dirpath: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn file_header(magic: &[u8; 4]) -> [u8; FILE_HEADER_SIZE as usize] {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    header[..4].copy_from_slice(magic);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// Reads and validates the header at the start of a data or hint file,
+// returning the format version it was written with.
+fn check_file_header<R: Read>(reader: &mut R, magic: &[u8; 4]) -> Result<u32, BitcaskError> {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    if header[..4] != *magic {
+        return Err(BitcaskError::InvalidFileFormat);
+    }
+    let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(BitcaskError::UnsupportedVersion(version));
+    }
+    Ok(version)
+}
+
+// Whether a record read from a file of format `version` is a delete.
+fn is_delete(version: u32, flags: u8, value: &[u8]) -> bool {
+    if version < 2 {
+        return flags == 0 && value == LEGACY_TOMBSTONE;
+    }
+    flags & FLAG_DELETE != 0
+}
+
+// Opens a data file for appending, writing its header if it's new.
+fn open_data_file(filepath: &Path) -> io::Result<fs::File> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(filepath)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(&file_header(DATA_MAGIC))?;
+    }
+    Ok(file)
+}
+
+fn read_meta(dirpath: &Path) -> Result<BTreeMap<String, Vec<u8>>, BitcaskError> {
+    let mut meta = BTreeMap::new();
+    let bytes = match fs::read(dirpath.join(META_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(meta),
+        Err(e) => return Err(e.into()),
+    };
+    let mut rest = bytes.as_slice();
+    check_file_header(&mut rest, META_MAGIC)?;
+    while !rest.is_empty() {
+        let sizes = rest.get(..16).ok_or(BitcaskError::InvalidFileFormat)?;
+        let key_size = u64::from_le_bytes(sizes[..8].try_into().unwrap());
+        let value_size = u64::from_le_bytes(sizes[8..].try_into().unwrap());
+        rest = &rest[16..];
+        let fits = key_size
+            .checked_add(value_size)
+            .is_some_and(|size| size <= rest.len() as u64);
+        if !fits {
+            return Err(BitcaskError::InvalidFileFormat);
+        }
+        let (key, value) = rest[..(key_size + value_size) as usize].split_at(key_size as usize);
+        let key = String::from_utf8(key.to_vec()).map_err(|_| BitcaskError::InvalidFileFormat)?;
+        meta.insert(key, value.to_vec());
+        rest = &rest[(key_size + value_size) as usize..];
+    }
+    Ok(meta)
+}
+
+// Replaces the meta file with `meta`, by way of a temporary file so a crash
+// leaves either the old contents or the new.
+fn write_meta(dirpath: &Path, meta: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+    let mut bytes = file_header(META_MAGIC).to_vec();
+    for (key, value) in meta {
+        bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(value);
+    }
+    let temp_filepath = dirpath.join(format!("{}.{}", META_FILE, MERGING_EXT));
+    let mut file = fs::File::create(&temp_filepath)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(temp_filepath, dirpath.join(META_FILE))?;
+    sync_dir(dirpath)
+}
+
+// Deletes the output of a merge that never finished.
+fn remove_merge_leftovers(layout: Layout, dirpath: &Path) -> io::Result<()> {
+    for filepath in layout.entries(dirpath)? {
+        if filepath.extension().is_some_and(|ext| ext == MERGING_EXT) {
+            fs::remove_file(filepath)?;
+        }
+    }
+    Ok(())
+}
+
+// Cuts a partial record a crash left at the end of the newest data file
+// back off, so the file ends on a record boundary again. Only the newest
+// file was being written to; a hinted one was sealed in full.
+fn truncate_partial_tail(
+    layout: Layout,
+    dirpath: &Path,
+    recovery_mode: RecoveryMode,
+) -> Result<(), BitcaskError> {
+    let newest = layout
+        .entries(dirpath)?
+        .into_iter()
+        .filter(|filepath| filepath.extension().is_some_and(|ext| ext == "dat"))
+        .filter_map(|filepath| Some((store_file_id(&filepath)?, filepath)))
+        .max_by_key(|(file_id, _)| *file_id);
+    let (file_id, filepath) = match newest {
+        Some(newest) => newest,
+        None => return Ok(()),
+    };
+    if filepath.with_extension("hint").exists() {
+        return Ok(());
+    }
+    let (_, valid_len, _) = scan_data_file_len(&filepath, file_id, recovery_mode)?;
+    let file = fs::OpenOptions::new().write(true).open(&filepath)?;
+    if file.metadata()?.len() > valid_len {
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Removes the clean-close marker, returning whether there was one. A
+// directory without any store files has nothing to recover, so it counts as
+// cleanly closed.
+fn take_clean_marker(layout: Layout, dirpath: &Path) -> io::Result<bool> {
+    match fs::remove_file(dirpath.join(CLEAN_FILE)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(!layout
+            .entries(dirpath)?
+            .iter()
+            .any(|filepath| store_file_id(filepath).is_some())),
+        Err(e) => Err(e),
+    }
+}
+
+// Takes the directory's writer lock, failing if another handle holds it.
+fn lock_dir(dirpath: &Path) -> Result<fs::File, BitcaskError> {
+    let lock_file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dirpath.join(LOCK_FILE))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(lock_file),
+        Err(fs::TryLockError::WouldBlock) => Err(BitcaskError::AlreadyLocked),
+        Err(fs::TryLockError::Error(e)) => Err(BitcaskError::Io(e)),
+    }
+}
+
+// Only the spelling the store writes ids in counts, so no two names in a
+// directory can stand for the same id; `007.dat` is someone else's file.
+fn get_file_id(filepath: &Path) -> Option<u64> {
+    let stem = filepath.file_stem()?.to_str()?;
+    let file_id = stem.parse::<u64>().ok()?;
+    (file_id.to_string() == stem).then_some(file_id)
+}
+
+// The store owns exactly the files with a numeric stem and a `.dat` or
+// `.hint` extension. Scans, merges and clears leave everything else alone.
+fn store_file_id(filepath: &Path) -> Option<u64> {
+    let extension = filepath.extension()?;
+    if extension != "dat" && extension != "hint" {
+        return None;
+    }
+    get_file_id(filepath)
+}
+
+// Where a store keeps its files: directly in its directory, or spread over
+// `shards` numbered subdirectories by file id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Layout {
+    shards: u32,
+}
+
+impl Layout {
+    fn dirs(&self, root: &Path) -> Vec<PathBuf> {
+        if self.shards == 0 {
+            return vec![root.to_path_buf()];
+        }
+        (0..self.shards)
+            .map(|shard| root.join(format!("{:03}", shard)))
+            .collect()
+    }
+
+    fn dir_of(&self, root: &Path, file_id: u64) -> PathBuf {
+        if self.shards == 0 {
+            return root.to_path_buf();
+        }
+        root.join(format!("{:03}", file_id % self.shards as u64))
+    }
+
+    fn path(&self, root: &Path, file_id: u64, ext: &str) -> PathBuf {
+        self.dir_of(root, file_id)
+            .join(format!("{}.{}", file_id, ext))
+    }
+
+    fn create_dirs(&self, root: &Path) -> io::Result<()> {
+        for dir in self.dirs(root) {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    // Every entry of the directories files can live in. A shard directory
+    // that doesn't exist holds nothing.
+    fn entries(&self, root: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut entries = Vec::new();
+        for dir in self.dirs(root) {
+            let dir_entries = match dir.read_dir() {
+                Ok(dir_entries) => dir_entries,
+                Err(e) if self.shards > 0 && e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in dir_entries {
+                entries.push(entry?.path());
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Returns the key dir along with how many unreadable records lenient
+// recovery skipped on the way.
+fn build_keydir<P: AsRef<Path>>(
+    path: P,
+    layout: Layout,
+    recovery_mode: RecoveryMode,
+    capacity: Option<usize>,
+    now: u64,
+) -> Result<(HashMap<Vec<u8>, KeyDir>, u64), BitcaskError> {
+    let dir: &Path = path.as_ref();
+    if !dir.exists() {
+        return Err(BitcaskError::DirNotFound);
+    }
+    let mut sorted_entries = layout.entries(dir)?;
+    sorted_entries.sort_by_key(|entry| get_file_id(entry));
+    // Without a hint from the caller, guess from how much there is to read
+    // back out of hint files.
+    let capacity = capacity.unwrap_or_else(|| {
+        let hint_bytes: u64 = sorted_entries
+            .iter()
+            .filter(|entry| entry.extension().is_some_and(|ext| ext == "hint"))
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        (hint_bytes / AVG_HINT_ENTRY_SIZE) as usize
+    });
+    let mut map = HashMap::with_capacity(capacity);
+    let mut skipped = 0;
+    let mut processed: HashSet<u64> = HashSet::new();
+    for entry in sorted_entries {
+        if entry.extension().is_none_or(|ext| ext != "dat") {
+            continue;
+        }
+        let file_id = match store_file_id(&entry) {
+            Some(id) => id,
+            None => {
+                continue;
+            }
+        };
+        // Skipping either file would drop its records without a word.
+        if !processed.insert(file_id) {
+            return Err(BitcaskError::DuplicateFileId(file_id));
+        }
+        let hint_filepath = entry.with_extension("hint");
+        // A hint that fails its trailer is passed over for the data itself.
+        let hinted = if hint_filepath.exists() {
+            read_hint_file(&hint_filepath, &entry, file_id)?
+        } else {
+            None
+        };
+        let entries = match hinted {
+            Some(entries) => entries,
+            None => {
+                let (entries, _, file_skipped) =
+                    scan_data_file_len(&entry, file_id, recovery_mode)?;
+                skipped += file_skipped;
+                entries
+            }
+        };
+        // Records come in write order, so the last one for a key wins and a
+        // tombstone or expired value takes the key out altogether.
+        for (key, map_entry) in entries {
+            if !map_entry.is_live(now) {
+                map.remove(&key);
+            } else {
+                map.insert(key, map_entry);
+            }
+        }
+    }
+    Ok((map, skipped))
+}
+
+// Older values of live keys kept through a merge, by the file id and value
+// position of each key's newest record.
+type KeptVersions = HashMap<(u64, u64), VecDeque<KeyDir>>;
+
+// The index entries of one data file, in the order they were written.
+type FileEntries = Vec<(Vec<u8>, KeyDir)>;
+
+// Reads the index entries a hint file stores for the data file `file_id`,
+// or returns `None` if its trailer doesn't match them.
+fn read_hint_file(
+    hint_filepath: &Path,
+    dat_filepath: &Path,
+    file_id: u64,
+) -> Result<Option<FileEntries>, BitcaskError> {
+    let hint = fs::read(hint_filepath)?;
+    let mut hint_file = hint.as_slice();
+    let mut dat_file = fs::File::open(dat_filepath)?;
+    let hint_version = check_file_header(&mut hint_file, HINT_MAGIC)?;
+    let mut expected_count = None;
+    if hint_version >= 3 {
+        match check_hint_trailer(hint_file) {
+            Some((hinted, count)) => {
+                hint_file = hinted;
+                expected_count = Some(count);
+            }
+            None => return Ok(None),
+        }
+    }
+    let file_len = FILE_HEADER_SIZE + hint_file.len() as u64;
+    let mut entries = Vec::with_capacity((file_len / AVG_HINT_ENTRY_SIZE) as usize);
+    let dat_len = dat_file.metadata()?.len();
+    let mut buf = [0u8; 8];
+    // How deletes are marked depends on the data file's version.
+    let version = check_file_header(&mut dat_file, DATA_MAGIC)?;
+    let delete_size = if version < 2 {
+        LEGACY_TOMBSTONE.len() as u64
+    } else {
+        0
+    };
+    let mut file_pos = FILE_HEADER_SIZE;
+
+    while file_pos < file_len {
+        if file_len - file_pos < 8 * 5 {
+            return Err(BitcaskError::InvalidFileFormat);
+        }
+
+        // timestamp
+        let _ = hint_file.read_exact(&mut buf);
+        let timestamp = u64::from_le_bytes(buf);
+        file_pos += 8;
+
+        // expiry
+        let _ = hint_file.read_exact(&mut buf);
+        let expiry = u64::from_le_bytes(buf);
+        file_pos += 8;
+
+        // key size
+        let _ = hint_file.read_exact(&mut buf);
+        let key_size = u64::from_le_bytes(buf);
+        file_pos += 8;
+
+        // value size
+        let _ = hint_file.read_exact(&mut buf);
+        let value_size = u64::from_le_bytes(buf);
+        file_pos += 8;
+
+        // value pos
+        let _ = hint_file.read_exact(&mut buf);
+        let value_pos = u64::from_le_bytes(buf);
+        file_pos += 8;
+
+        // Sizes are checked against the files before anything is allocated
+        // from them.
+        let record_fits = value_pos
+            .checked_sub(HEADER_SIZE)
+            .and_then(|pos| pos.checked_sub(key_size))
+            .is_some()
+            && value_pos
+                .checked_add(value_size)
+                .is_some_and(|end| end <= dat_len);
+        if key_size > file_len - file_pos || !record_fits {
+            return Err(BitcaskError::InvalidFileFormat);
+        }
+
+        // key
+        let mut key = vec![0u8; key_size as usize];
+
+        let _ = hint_file.read_exact(&mut key);
+        file_pos += key_size;
+
+        // Hints written by generate_hint can point at deletes, which only
+        // the data file can tell apart.
+        let tombstone = if value_size == delete_size {
+            let record_size = HEADER_SIZE + key_size + value_size;
+            let mut record = vec![0u8; record_size as usize];
+            read_exact_at(&dat_file, &mut record, value_pos - HEADER_SIZE - key_size)?;
+            let value = &record[(HEADER_SIZE + key_size) as usize..];
+            is_delete(version, record[FLAGS_OFFSET], value)
+        } else {
+            false
+        };
+
+        let map_entry = KeyDir {
+            file_id,
+            value_size,
+            value_pos,
+            timestamp,
+            expiry,
+            tombstone,
+        };
+
+        entries.push((key, map_entry));
+    }
+    if expected_count.is_some_and(|count| count != entries.len() as u64) {
+        return Ok(None);
+    }
+    Ok(Some(entries))
+}
+
+// What a scan found at one position of a data file.
+enum Scanned {
+    Record { key: Vec<u8>, kd: KeyDir },
+    // Filler ahead of an aligned record.
+    Padding,
+    // The record runs past the end of the file.
+    Truncated,
+    // The record is all there but fails its checksum.
+    Corrupt { value_pos: u64 },
+}
+
+// Size of the buffer data files are scanned through.
+const SCAN_BUFFER_SIZE: usize = 64 * 1024;
+
+// Parses and checks the record starting at `*pos`, reading sequentially from
+// `reader`, which must sit at `*pos`. `*pos` follows the reader along.
+fn scan_record<R: Read>(
+    reader: &mut R,
+    file_id: u64,
+    version: u32,
+    pos: &mut u64,
+    file_len: u64,
+) -> io::Result<Scanned> {
+    let record_pos = *pos;
+    if file_len - record_pos < HEADER_SIZE {
+        return Ok(Scanned::Truncated);
+    }
+    let mut header = [0u8; HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    *pos += HEADER_SIZE;
+    let field = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+    let crc = field(0);
+    let timestamp = field(8);
+    let expiry = field(16);
+    let flags = header[FLAGS_OFFSET];
+    let key_size = field(FLAGS_OFFSET + 1);
+    let value_size = field(FLAGS_OFFSET + 9);
+
+    let record_end = key_size
+        .checked_add(value_size)
+        .and_then(|size| size.checked_add(record_pos + HEADER_SIZE));
+    if record_end.is_none_or(|end| end > file_len) {
+        return Ok(Scanned::Truncated);
+    }
+    let mut body = vec![0u8; (key_size + value_size) as usize];
+    reader.read_exact(&mut body)?;
+    *pos += key_size + value_size;
+    let value_pos = record_pos + HEADER_SIZE + key_size;
+    if crc32(&[&header[8..], &body]) as u64 != crc {
+        return Ok(Scanned::Corrupt { value_pos });
+    }
+    if flags & FLAG_PADDING != 0 {
+        return Ok(Scanned::Padding);
+    }
+    let value = body.split_off(key_size as usize);
+    let kd = KeyDir {
+        file_id,
+        value_size,
+        value_pos,
+        timestamp,
+        expiry,
+        tombstone: is_delete(version, flags, &value),
+    };
+    Ok(Scanned::Record { key: body, kd })
+}
+
+// Indexes every record of a data file, in the order they were written.
+fn scan_data_file(
+    filepath: &Path,
+    file_id: u64,
+    recovery_mode: RecoveryMode,
+) -> Result<FileEntries, BitcaskError> {
+    Ok(scan_data_file_len(filepath, file_id, recovery_mode)?.0)
+}
+
+// Like `scan_data_file`, but also returns where the last intact record
+// ends, and how many unreadable records lenient recovery skipped. Anything
+// past the end is a partial or unreadable tail.
+fn scan_data_file_len(
+    filepath: &Path,
+    file_id: u64,
+    recovery_mode: RecoveryMode,
+) -> Result<(FileEntries, u64, u64), BitcaskError> {
+    let mut entries = Vec::new();
+    let dat_file = fs::File::open(filepath)?;
+    let file_len = dat_file.metadata()?.len();
+    // A file whose header never made it to disk was created just before a
+    // crash and holds nothing.
+    if file_len < FILE_HEADER_SIZE {
+        return Ok((entries, file_len, 0));
+    }
+    let mut reader = BufReader::with_capacity(SCAN_BUFFER_SIZE, dat_file);
+    let version = check_file_header(&mut reader, DATA_MAGIC)?;
+    let mut reader_pos = FILE_HEADER_SIZE;
+    let mut file_pos = FILE_HEADER_SIZE;
+    let mut valid_len = FILE_HEADER_SIZE;
+    let mut skipped = 0;
+    let mut resyncing = false;
+
+    while file_pos < file_len {
+        // Only a resync moves backwards, and then rarely past the buffer.
+        reader.seek_relative(file_pos as i64 - reader_pos as i64)?;
+        reader_pos = file_pos;
+        let scanned = scan_record(&mut reader, file_id, version, &mut reader_pos, file_len)?;
+        let corrupt_at = match scanned {
+            Scanned::Record { key, kd } => {
+                entries.push((key, kd));
+                file_pos = reader_pos;
+                valid_len = file_pos;
+                resyncing = false;
+                continue;
+            }
+            Scanned::Padding => {
+                file_pos = reader_pos;
+                valid_len = file_pos;
+                resyncing = false;
+                continue;
+            }
+            Scanned::Truncated => None,
+            Scanned::Corrupt { value_pos } => Some(value_pos),
+        };
+        if recovery_mode == RecoveryMode::Strict {
+            match corrupt_at {
+                Some(value_pos) => return Err(BitcaskError::CorruptRecord { file_id, value_pos }),
+                // A crash can leave a partial record at the end of the file.
+                // It was never acknowledged, so stop at it rather than index
+                // garbage.
+                None => break,
+            }
+        }
+        // Resync at the next offset that holds an intact record. A mangled
+        // size can make a record look truncated, so that gets the same
+        // treatment.
+        if !resyncing {
+            skipped += 1;
+            resyncing = true;
+        }
+        file_pos += 1;
+    }
+    Ok((entries, valid_len, skipped))
+}
+
+impl Bitcask {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BitcaskError> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    pub fn open_with_options<P: Into<PathBuf>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, BitcaskError> {
+        if options.read_only {
+            return Self::open_read_only_with_options(path, options);
+        }
+        let path: PathBuf = path.into();
+        let path: &Path = path.as_ref();
+        let layout = Layout {
+            shards: options.shards,
+        };
+        if !options.create_if_missing && !path.exists() {
+            return Err(BitcaskError::DirNotFound);
+        }
+        // Creates `path` itself along the way.
+        layout.create_dirs(path)?;
+        let file_id = gen_file_id(layout, path, options.clock.now())?;
+        let lock_file = lock_dir(path)?;
+        let clean_shutdown = take_clean_marker(layout, path)?;
+        remove_merge_leftovers(layout, path)?;
+        if !clean_shutdown {
+            truncate_partial_tail(layout, path, options.recovery_mode)?;
+        }
+        let filepath = layout.path(path, file_id, "dat");
+        let active_file = Arc::new(open_data_file(&filepath)?);
+        let flusher = match options.sync_policy {
+            SyncPolicy::Interval(interval) => {
+                Some(Flusher::spawn(Arc::clone(&active_file), interval)?)
+            }
+            _ => None,
+        };
+        // Covers the lock and active files, any shard directories, and the
+        // marker and any leftovers removed above.
+        for dir in layout.dirs(path) {
+            sync_dir(&dir)?;
+        }
+        sync_dir(path)?;
+        // Appends land at the end of whatever the file already holds, so
+        // positions have to be counted from there.
+        let writer_pos = active_file.metadata()?.len();
+        let (key_dir, skipped_records) = build_keydir(
+            path,
+            layout,
+            options.recovery_mode,
+            options.expected_keys,
+            options.clock.now(),
+        )?;
+        let meta = read_meta(path)?;
+        let mut bitcask = Bitcask {
+            key_dir,
+            active_file: Some(BufWriter::with_capacity(
+                options.write_buffer_size,
+                active_file,
+            )),
+            active_file_id: file_id,
+            writer_pos,
+            data_path: path.to_path_buf(),
+            max_file_size: options.max_file_size,
+            lock_file: Some(lock_file),
+            sync_policy: options.sync_policy,
+            unsynced_writes: 0,
+            flusher,
+            auto_merge_threshold: options.auto_merge_threshold,
+            total_bytes: 0,
+            dead_bytes: 0,
+            compression: options.compression,
+            max_value_size: options.max_value_size,
+            recovery_mode: options.recovery_mode,
+            read_handles: Mutex::new(HashMap::new()),
+            ordered_keys: (options.ordered_index && !options.hashed_keys).then(BTreeSet::new),
+            write_buffer_size: options.write_buffer_size,
+            encryption: options.encryption,
+            layout,
+            drop_deleted_keys: options.drop_deleted_keys,
+            hint_on_rotate: options.hint_on_rotate,
+            clean_shutdown,
+            observer: None,
+            merge_queue: Vec::new(),
+            hashed: options.hashed_keys.then(HashedKeyDir::new),
+            counters: Counters::default(),
+            record_alignment: options.record_alignment,
+            meta,
+            keep_versions: options.keep_versions,
+            clock: options.clock,
+            max_keys: options.max_keys,
+            skipped_records,
+            failed_auto_merges: 0,
+            auto_merge_error: None,
+        };
+        bitcask.hash_key_dir();
+        bitcask.rebuild_ordered_keys();
+        bitcask.recount_bytes()?;
+        Ok(bitcask)
+    }
+
+    /// Opens `path` for reading alongside a writer. The handle takes no lock,
+    /// creates no files, and rejects every write with `ReadOnly`; call
+    /// `reload` to pick up data written since.
+    pub fn open_read_only<P: Into<PathBuf>>(path: P) -> Result<Self, BitcaskError> {
+        Self::open_read_only_with_options(path, Options::default())
+    }
+
+    /// Like `open_read_only`, with the settings the store was written with:
+    /// its shards, encryption key, alignment and so on. Settings that only
+    /// matter to a writer are ignored. `open_with_options` comes here when
+    /// `read_only` is set.
+    pub fn open_read_only_with_options<P: Into<PathBuf>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, BitcaskError> {
+        let path: PathBuf = path.into();
+        let layout = Layout {
+            shards: options.shards,
+        };
+        let clean_shutdown = path.join(CLEAN_FILE).exists();
+        let (key_dir, skipped_records) = build_keydir(
+            &path,
+            layout,
+            options.recovery_mode,
+            options.expected_keys,
+            options.clock.now(),
+        )?;
+        let meta = read_meta(&path)?;
+        let mut bitcask = Bitcask {
+            key_dir,
+            active_file: None,
+            active_file_id: 0,
+            writer_pos: 0,
+            data_path: path,
+            max_file_size: options.max_file_size,
+            lock_file: None,
+            sync_policy: options.sync_policy,
+            unsynced_writes: 0,
+            flusher: None,
+            auto_merge_threshold: options.auto_merge_threshold,
+            total_bytes: 0,
+            dead_bytes: 0,
+            compression: options.compression,
+            max_value_size: options.max_value_size,
+            recovery_mode: options.recovery_mode,
+            read_handles: Mutex::new(HashMap::new()),
+            ordered_keys: (options.ordered_index && !options.hashed_keys).then(BTreeSet::new),
+            write_buffer_size: options.write_buffer_size,
+            encryption: options.encryption,
+            layout,
+            drop_deleted_keys: options.drop_deleted_keys,
+            hint_on_rotate: options.hint_on_rotate,
+            clean_shutdown,
+            observer: None,
+            merge_queue: Vec::new(),
+            hashed: options.hashed_keys.then(HashedKeyDir::new),
+            counters: Counters::default(),
+            record_alignment: options.record_alignment,
+            meta,
+            keep_versions: options.keep_versions,
+            clock: options.clock,
+            max_keys: options.max_keys,
+            skipped_records,
+            failed_auto_merges: 0,
+            auto_merge_error: None,
+        };
+        bitcask.hash_key_dir();
+        bitcask.rebuild_ordered_keys();
+        bitcask.recount_bytes()?;
+        Ok(bitcask)
+    }
+
+    /// Opens `path` and loads every entry from `iter` into it. Records are
+    /// buffered per data file and synced once at the end, and the key dir is
+    /// filled in as they're written, so this is much cheaper than calling
+    /// `put` for each entry.
+    pub fn import<P, I>(path: P, iter: I) -> Result<Self, BitcaskError>
+    where
+        P: Into<PathBuf>,
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut bitcask = Self::open(path)?;
+        let mut pending = Vec::new();
+        for (key, value) in iter {
+            Self::check_key(&key)?;
+            bitcask.check_value_size(&value)?;
+            let (flags, value) = bitcask.encode(&key, value)?;
+            let entry = DataFileEntry::with_timestamp(key, value, bitcask.now(), 0, flags);
+            let data = entry.to_bytes();
+            let record_pos = bitcask.writer_pos + pending.len() as u64;
+            let mut padding = bitcask.padding(record_pos);
+            if record_pos > FILE_HEADER_SIZE
+                && record_pos + (padding.len() + data.len()) as u64 > bitcask.max_file_size
+            {
+                bitcask.append(&pending)?;
+                bitcask.writer_pos += pending.len() as u64;
+                pending.clear();
+                bitcask.rotate()?;
+                padding = bitcask.padding(bitcask.writer_pos);
+            }
+            pending.extend_from_slice(&padding);
+            let kd_value = KeyDir {
+                file_id: bitcask.active_file_id,
+                value_size: entry.value_size,
+                value_pos: bitcask.writer_pos + pending.len() as u64 + HEADER_SIZE + entry.key_size,
+                timestamp: entry.timestamp,
+                expiry: entry.expiry,
+                tombstone: false,
+            };
+            pending.extend_from_slice(&data);
+            bitcask.index_record(entry.key, kd_value)?;
+        }
+        bitcask.append(&pending)?;
+        bitcask.writer_pos += pending.len() as u64;
+        bitcask.sync()?;
+        Ok(bitcask)
+    }
+
+    /// Rebuilds the key dir from the files currently on disk.
+    pub fn reload(&mut self) -> Result<(), BitcaskError> {
+        self.flush()?;
+        self.meta = read_meta(&self.data_path)?;
+        self.reindex()
+    }
+
+    // Builds the key dir afresh from the files on disk.
+    fn reindex(&mut self) -> Result<(), BitcaskError> {
+        let capacity = Some(self.index_entries().count());
+        (self.key_dir, self.skipped_records) = build_keydir(
+            &self.data_path,
+            self.layout,
+            self.recovery_mode,
+            capacity,
+            self.now(),
+        )?;
+        self.hash_key_dir();
+        // The writer may have merged away files we hold handles to.
+        self.read_handles.get_mut().unwrap().clear();
+        self.rebuild_ordered_keys();
+        self.recount_bytes()
+    }
+
+    fn rebuild_ordered_keys(&mut self) {
+        let now = self.now();
+        if let Some(keys) = &mut self.ordered_keys {
+            *keys = self
+                .key_dir
+                .iter()
+                .filter(|(_, kd)| kd.is_live(now))
+                .map(|(key, _)| key.clone())
+                .collect();
+        }
+    }
+
+    // An empty key is almost surely a caller bug, and would let a prefix
+    // scan for "" or a namespace's own prefix match keys unexpectedly.
+    fn check_key(key: &[u8]) -> Result<(), BitcaskError> {
+        if key.is_empty() {
+            return Err(BitcaskError::InvalidKey);
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    // Fails with `Full` unless the key dir has room for `new_keys` more.
+    fn check_room(&self, new_keys: usize) -> Result<(), BitcaskError> {
+        let held = match &self.hashed {
+            Some(hashed) => hashed.slots.len() + hashed.collided.len(),
+            None => self.key_dir.len(),
+        };
+        match self.max_keys {
+            Some(max_keys) if held + new_keys > max_keys => Err(BitcaskError::Full),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_value_size(&self, value: &[u8]) -> Result<(), BitcaskError> {
+        if value.len() as u64 > self.max_value_size {
+            return Err(BitcaskError::ValueTooLarge);
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), BitcaskError> {
+        match self.active_file {
+            Some(_) => Ok(()),
+            None => Err(BitcaskError::ReadOnly),
+        }
+    }
+
+    // Appends `data` to the active file. On failure whatever part of it made
+    // it out is cut off again so the next write still lands at `writer_pos`.
+    fn append(&mut self, data: &[u8]) -> Result<(), BitcaskError> {
+        let writer = self.active_file.as_mut().ok_or(BitcaskError::ReadOnly)?;
+        let e = match writer.write_all(data) {
+            Ok(()) => {
+                bump(&self.counters.bytes_written, data.len() as u64);
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        // Bytes before `writer_pos` are already indexed, so any of them still
+        // buffered go out ahead of the cut.
+        let (mut file, buffered) = self.active_file.take().unwrap().into_parts();
+        let buffered = buffered.unwrap_or_default();
+        let file_len = file.metadata().map_or(self.writer_pos, |m| m.len());
+        if file_len < self.writer_pos {
+            let pending = (self.writer_pos - file_len) as usize;
+            let _ = file.write_all(&buffered[..pending.min(buffered.len())]);
+        }
+        let _ = file.set_len(self.writer_pos);
+        self.active_file = Some(BufWriter::with_capacity(self.write_buffer_size, file));
+        Err(e.into())
+    }
+
+    // Derives total and dead byte counts from what's on disk and what the
+    // key dir still points at.
+    fn recount_bytes(&mut self) -> Result<(), BitcaskError> {
+        let mut total_bytes = 0;
+        for filepath in self.layout.entries(&self.data_path)? {
+            if filepath.extension().is_some_and(|ext| ext == "dat")
+                && get_file_id(&filepath).is_some()
+            {
+                // File headers are overhead no merge can reclaim.
+                total_bytes += fs::metadata(&filepath)?
+                    .len()
+                    .saturating_sub(self.file_overhead());
+            }
+        }
+        let live_bytes = self.live_bytes()?;
+        self.total_bytes = total_bytes;
+        self.dead_bytes = total_bytes.saturating_sub(live_bytes);
+        Ok(())
+    }
+
+    // Bytes of the records a merge would keep: each live key's newest value,
+    // plus the older versions `keep_versions` holds on to, which are nothing
+    // a merge can reclaim either.
+    fn live_bytes(&self) -> Result<u64, BitcaskError> {
+        let now = self.now();
+        let kept = self.kept_versions()?;
+        Ok(self
+            .index_entries()
+            .filter(|(_, kd)| kd.is_live(now))
+            .flat_map(|(key_size, newest)| {
+                let older = kept.get(&(newest.file_id, newest.value_pos));
+                older
+                    .into_iter()
+                    .flatten()
+                    .chain([newest])
+                    .map(move |kd| (key_size, kd))
+            })
+            .map(|(key_size, kd)| self.padded_size(HEADER_SIZE + key_size + kd.value_size))
+            .sum())
+    }
+
+    /// How many operations of each kind this handle has served so far.
+    pub fn metrics(&self) -> Metrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let counters = &self.counters;
+        Metrics {
+            puts: load(&counters.puts),
+            gets: load(&counters.gets),
+            deletes: load(&counters.deletes),
+            handle_hits: load(&counters.handle_hits),
+            handle_misses: load(&counters.handle_misses),
+            merges: load(&counters.merges),
+            bytes_written: load(&counters.bytes_written),
+        }
+    }
+
+    /// Total bytes of every data and hint file, counting writes still in
+    /// the buffer. Only file metadata is read.
+    pub fn size_on_disk(&self) -> Result<u64, BitcaskError> {
+        let mut size = self.buffered_len();
+        for filepath in self.layout.entries(&self.data_path)? {
+            if store_file_id(&filepath).is_some() {
+                size += fs::metadata(&filepath)?.len();
+            }
+        }
+        Ok(size)
+    }
+
+    /// `(file_id, bytes)` of every data file, in id order, counting writes
+    /// still in the buffer towards the active file.
+    pub fn file_sizes(&self) -> Result<Vec<(u64, u64)>, BitcaskError> {
+        let mut sizes = Vec::new();
+        for filepath in self.layout.entries(&self.data_path)? {
+            if let Some(file_id) = store_file_id(&filepath) {
+                if filepath.extension().is_some_and(|ext| ext == "dat") {
+                    let mut size = fs::metadata(&filepath)?.len();
+                    if file_id == self.active_file_id {
+                        size += self.buffered_len();
+                    }
+                    sizes.push((file_id, size));
+                }
+            }
+        }
+        sizes.sort_unstable();
+        Ok(sizes)
+    }
+
+    fn buffered_len(&self) -> u64 {
+        self.active_file
+            .as_ref()
+            .map_or(0, |writer| writer.buffer().len() as u64)
+    }
+
+    /// Scans the directory for file counts and sizes, and the key dir for
+    /// live keys and tombstones.
+    pub fn stats(&self) -> Result<BitcaskStats, BitcaskError> {
+        let mut data_files = 0;
+        let mut total_bytes = 0;
+        for filepath in self.layout.entries(&self.data_path)? {
+            if filepath.extension().is_some_and(|ext| ext == "dat")
+                && get_file_id(&filepath).is_some()
+            {
+                data_files += 1;
+                total_bytes += fs::metadata(&filepath)?.len();
+            }
+        }
+        if let Some(writer) = &self.active_file {
+            total_bytes += writer.buffer().len() as u64;
+        }
+        let overhead = data_files as u64 * self.file_overhead();
+        let now = self.now();
+        let mut live_keys = 0;
+        let mut tombstones = 0;
+        for (_, kd) in self.index_entries() {
+            if kd.tombstone {
+                tombstones += 1;
+            } else if kd.is_live(now) {
+                live_keys += 1;
+            }
+        }
+        let live_bytes = self.live_bytes()?;
+        Ok(BitcaskStats {
+            data_files,
+            total_bytes,
+            live_keys,
+            tombstones,
+            reclaimable_bytes: total_bytes.saturating_sub(overhead + live_bytes),
+            dead_bytes: self.dead_bytes,
+            skipped_records: self.skipped_records,
+            failed_auto_merges: self.failed_auto_merges,
+        })
+    }
+
+    /// Reads every record of every data file and checks its checksum,
+    /// without changing anything. Writes still in the write buffer aren't on
+    /// disk yet, so they aren't covered.
+    pub fn verify(&self) -> Result<VerifyReport, BitcaskError> {
+        let mut data_files = Vec::new();
+        for filepath in self.layout.entries(&self.data_path)? {
+            if let Some(file_id) = store_file_id(&filepath) {
+                if filepath.extension().is_some_and(|ext| ext == "dat") {
+                    data_files.push((file_id, filepath));
+                }
+            }
+        }
+        data_files.sort();
+        let mut report = VerifyReport::default();
+        for (file_id, filepath) in data_files {
+            let data_file = fs::File::open(&filepath)?;
+            let file_len = data_file.metadata()?.len();
+            if file_len < FILE_HEADER_SIZE {
+                continue;
+            }
+            let mut reader = BufReader::with_capacity(SCAN_BUFFER_SIZE, data_file);
+            let version = check_file_header(&mut reader, DATA_MAGIC)?;
+            let mut pos = FILE_HEADER_SIZE;
+            while pos < file_len {
+                let record_pos = pos;
+                let scanned = scan_record(&mut reader, file_id, version, &mut pos, file_len)?;
+                if !matches!(scanned, Scanned::Padding) {
+                    report.total_records += 1;
+                }
+                match scanned {
+                    Scanned::Record { .. } | Scanned::Padding => {}
+                    Scanned::Corrupt { .. } => report.corrupt_records.push((file_id, record_pos)),
+                    // Nothing after a record of unknown length can be found.
+                    Scanned::Truncated => {
+                        report.corrupt_records.push((file_id, record_pos));
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fraction of on-disk data bytes that a merge would reclaim.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / self.total_bytes as f64
+    }
+
+    // Seals the active file and starts writing to a fresh one.
+    fn rotate(&mut self) -> Result<(), BitcaskError> {
+        self.sync()?;
+        if self.hint_on_rotate && self.active_file.is_some() {
+            self.write_hint(self.active_file_id)?;
+        }
+        let file_id = gen_file_id(self.layout, &self.data_path, self.now())?;
+        let filepath = self.layout.path(&self.data_path, file_id, "dat");
+        let writer =
+            BufWriter::with_capacity(self.write_buffer_size, Arc::new(open_data_file(&filepath)?));
+        // The sealed file stays readable through the handle it was written
+        // with.
+        if let Some(flusher) = &self.flusher {
+            flusher.set_file(Arc::clone(writer.get_ref()));
+        }
+        if let Some(sealed) = self.active_file.replace(writer) {
+            let handle = Arc::clone(sealed.get_ref());
+            self.read_handles
+                .get_mut()
+                .unwrap()
+                .insert(self.active_file_id, handle);
+        }
+        sync_dir(&self.layout.dir_of(&self.data_path, file_id))?;
+        self.active_file_id = file_id;
+        self.writer_pos = FILE_HEADER_SIZE;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, BitcaskError> {
+        bump(&self.counters.gets, 1);
+        let kd_value = self.lookup(key)?;
+        match kd_value {
+            // The key dir knows which entries are deletes, so those never
+            // touch the disk.
+            Some(kd) if kd.is_live(self.now()) => self.read_value(key, kd),
+            _ => Err(BitcaskError::KeyNotFound),
+        }
+    }
+
+    /// Like `get`, but reads the value into `buf`, replacing what it held,
+    /// and returns its length. Reusing one buffer across calls saves an
+    /// allocation per read; compressed and encrypted values still need one
+    /// to decode.
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<usize, BitcaskError> {
+        bump(&self.counters.gets, 1);
+        match self.lookup(key)? {
+            Some(kd) if kd.is_live(self.now()) => self.read_value_into(key, kd, buf)?,
+            _ => return Err(BitcaskError::KeyNotFound),
+        }
+        Ok(buf.len())
+    }
+
+    /// Streams the value for `key` straight from its data file instead of
+    /// reading it into memory first. The record's CRC is checked as it goes,
+    /// and a mismatch is an `InvalidData` error from the final read.
+    /// Compressed and encrypted values can only be checked whole, so those
+    /// are decoded up front and served from memory.
+    pub fn value_reader(&self, key: &[u8]) -> Result<impl Read, BitcaskError> {
+        bump(&self.counters.gets, 1);
+        let kd = match self.lookup(key)? {
+            Some(kd) if kd.is_live(self.now()) => kd,
+            _ => return Err(BitcaskError::KeyNotFound),
+        };
+        if let Some(result) = self.buffered_value(key, kd) {
+            return Ok(ValueReader::Decoded(io::Cursor::new(result?)));
+        }
+        let corrupt = || BitcaskError::CorruptRecord {
+            file_id: kd.file_id,
+            value_pos: kd.value_pos,
+        };
+        let data_file = self.read_handle(kd.file_id)?;
+        let header_size = HEADER_SIZE + key.len() as u64;
+        let record_pos = kd.value_pos.checked_sub(header_size).ok_or_else(corrupt)?;
+        if kd.value_pos.saturating_add(kd.value_size) > data_file.metadata()?.len() {
+            return Err(corrupt());
+        }
+        let mut header = vec![0u8; header_size as usize];
+        read_exact_at(&data_file, &mut header, record_pos)?;
+        if header[FLAGS_OFFSET] & (FLAG_LZ4 | FLAG_SNAPPY | FLAG_ENCRYPTED) != 0 {
+            let value = self.read_value_from(&data_file, key, kd)?;
+            return Ok(ValueReader::Decoded(io::Cursor::new(value)));
+        }
+        if header[HEADER_SIZE as usize..] != *key {
+            return Err(corrupt());
+        }
+        // A handle of its own, so its cursor is nobody else's.
+        let mut file = fs::File::open(self.layout.path(&self.data_path, kd.file_id, "dat"))?;
+        file.seek(io::SeekFrom::Start(kd.value_pos))?;
+        Ok(ValueReader::File {
+            data: file.take(kd.value_size),
+            crc: crc32_update(!0, &header[8..]),
+            stored_crc: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+        })
+    }
+
+    /// Like `get`, but also returns the record's metadata.
+    pub fn get_entry(&self, key: &[u8]) -> Result<EntryMeta, BitcaskError> {
+        bump(&self.counters.gets, 1);
+        match self.lookup(key)? {
+            Some(kd) if kd.is_live(self.now()) => Ok(EntryMeta {
+                value: self.read_value(key, kd)?,
+                timestamp: kd.timestamp,
+                file_id: kd.file_id,
+                value_pos: kd.value_pos,
+            }),
+            _ => Err(BitcaskError::KeyNotFound),
+        }
+    }
+
+    /// Looks up every key in `keys`, opening each data file involved only
+    /// once. Results come back in the same order as `keys`.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Vec<Result<Vec<u8>, BitcaskError>> {
+        bump(&self.counters.gets, keys.len() as u64);
+        let now = self.now();
+        let mut results: Vec<Option<Result<Vec<u8>, BitcaskError>>> =
+            keys.iter().map(|_| None).collect();
+        let mut by_file: HashMap<u64, Vec<(usize, &KeyDir)>> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            match self.lookup(key) {
+                Ok(Some(kd)) if kd.is_live(now) => {
+                    by_file.entry(kd.file_id).or_default().push((i, kd))
+                }
+                Ok(_) => results[i] = Some(Err(BitcaskError::KeyNotFound)),
+                Err(e) => results[i] = Some(Err(e)),
+            }
+        }
+        for (file_id, lookups) in by_file {
+            match self.read_handle(file_id) {
+                Ok(data_file) => {
+                    for (i, kd) in lookups {
+                        results[i] = Some(match self.buffered_value(keys[i], kd) {
+                            Some(result) => result,
+                            None => self.read_value_from(&data_file, keys[i], kd),
+                        });
+                    }
+                }
+                // Each key opens the file again for an error of its own, so
+                // none of them loses the OS error the first open got.
+                Err(_) => {
+                    for (i, kd) in lookups {
+                        results[i] = Some(self.read_value(keys[i], kd));
+                    }
+                }
+            }
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn read_value(&self, key: &[u8], kd: &KeyDir) -> Result<Vec<u8>, BitcaskError> {
+        let mut buf = Vec::new();
+        self.read_value_into(key, kd, &mut buf)?;
+        Ok(buf)
+    }
+
+    // Like `read_value`, but into `buf`, so a caller that reads in a loop
+    // can reuse one allocation.
+    fn read_value_into(
+        &self,
+        key: &[u8],
+        kd: &KeyDir,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), BitcaskError> {
+        match self.buffered_record(key, kd) {
+            Some(record) => {
+                buf.clear();
+                buf.extend_from_slice(record?);
+            }
+            None => {
+                let data_file = self.read_handle(kd.file_id)?;
+                self.read_record(&data_file, key, kd, buf)?;
+            }
+        }
+        self.check_record(buf, key, kd)
+    }
+
+    // Serves a record that is still in the write buffer rather than on disk,
+    // or returns `None` if it has already been written out.
+    fn buffered_value(&self, key: &[u8], kd: &KeyDir) -> Option<Result<Vec<u8>, BitcaskError>> {
+        let record = self.buffered_record(key, kd)?;
+        Some(record.and_then(|record| {
+            let mut buf = record.to_vec();
+            self.check_record(&mut buf, key, kd)?;
+            Ok(buf)
+        }))
+    }
+
+    // The still unchecked record for `key` in the write buffer, or `None` if
+    // it has already been written out. Each append goes into the buffer whole
+    // or not at all, so a record is never split.
+    fn buffered_record(&self, key: &[u8], kd: &KeyDir) -> Option<Result<&[u8], BitcaskError>> {
+        let buffered = self.active_file.as_ref()?.buffer();
+        let flushed_pos = self.writer_pos - buffered.len() as u64;
+        if kd.file_id != self.active_file_id || kd.value_pos + kd.value_size <= flushed_pos {
+            return None;
+        }
+        let corrupt = BitcaskError::CorruptRecord {
+            file_id: kd.file_id,
+            value_pos: kd.value_pos,
+        };
+        let record_pos = kd.value_pos.checked_sub(HEADER_SIZE + key.len() as u64);
+        let start = match record_pos.and_then(|pos| pos.checked_sub(flushed_pos)) {
+            Some(start) => start as usize,
+            None => return Some(Err(corrupt)),
+        };
+        let end = (kd.value_pos + kd.value_size - flushed_pos) as usize;
+        Some(buffered.get(start..end).ok_or(corrupt))
+    }
+
+    // Returns a cached read handle for `file_id`, opening it on first use.
+    fn read_handle(&self, file_id: u64) -> io::Result<Arc<fs::File>> {
+        if let Some(writer) = &self.active_file {
+            if file_id == self.active_file_id {
+                bump(&self.counters.handle_hits, 1);
+                return Ok(Arc::clone(writer.get_ref()));
+            }
+        }
+        let mut handles = self.read_handles.lock().unwrap();
+        if let Some(handle) = handles.get(&file_id) {
+            bump(&self.counters.handle_hits, 1);
+            return Ok(Arc::clone(handle));
+        }
+        bump(&self.counters.handle_misses, 1);
+        let filepath = self.layout.path(&self.data_path, file_id, "dat");
+        let handle = Arc::new(fs::File::open(filepath)?);
+        if handles.len() >= MAX_READ_HANDLES {
+            handles.clear();
+        }
+        handles.insert(file_id, Arc::clone(&handle));
+        Ok(handle)
+    }
+
+    // Reads back the whole record for `key` and checks its CRC before handing
+    // out the value.
+    fn read_value_from(
+        &self,
+        data_file: &fs::File,
+        key: &[u8],
+        kd: &KeyDir,
+    ) -> Result<Vec<u8>, BitcaskError> {
+        let mut buf = Vec::new();
+        self.read_record(data_file, key, kd, &mut buf)?;
+        self.check_record(&mut buf, key, kd)?;
+        Ok(buf)
+    }
+
+    // Reads the whole record for `key` into `buf`, unchecked.
+    fn read_record(
+        &self,
+        data_file: &fs::File,
+        key: &[u8],
+        kd: &KeyDir,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), BitcaskError> {
+        let corrupt = BitcaskError::CorruptRecord {
+            file_id: kd.file_id,
+            value_pos: kd.value_pos,
+        };
+        let key_size = key.len() as u64;
+        let record_pos = match kd.value_pos.checked_sub(HEADER_SIZE + key_size) {
+            Some(pos) => pos,
+            None => return Err(corrupt),
+        };
+        // Never trust a recorded size with an allocation the file can't back.
+        let record_size = HEADER_SIZE + key_size + kd.value_size;
+        if record_pos.saturating_add(record_size) > data_file.metadata()?.len() {
+            return Err(corrupt);
+        }
+        buf.resize(record_size as usize, 0);
+        read_exact_at(data_file, buf, record_pos)?;
+        Ok(())
+    }
+
+    // Verifies a whole record read back for `key` into `buf` and leaves just
+    // its decoded value there. A stored value needing no decoding is shifted
+    // down in place.
+    fn check_record(&self, buf: &mut Vec<u8>, key: &[u8], kd: &KeyDir) -> Result<(), BitcaskError> {
+        let corrupt = BitcaskError::CorruptRecord {
+            file_id: kd.file_id,
+            value_pos: kd.value_pos,
+        };
+        let header_len = HEADER_SIZE as usize + key.len();
+        if buf.len() < header_len {
+            return Err(corrupt);
+        }
+        let (header, value) = buf.split_at(header_len);
+        let stored_crc = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let crc = crc32(&[&header[8..], value]) as u64;
+        if stored_crc != crc || header[HEADER_SIZE as usize..] != *key {
+            return Err(corrupt);
+        }
+        let flags = header[FLAGS_OFFSET];
+        if flags & (FLAG_ENCRYPTED | FLAG_LZ4 | FLAG_SNAPPY) == 0 {
+            buf.drain(..header_len);
+            return Ok(());
+        }
+        let value = if flags & FLAG_ENCRYPTED != 0 {
+            let config = self.encryption.as_ref().ok_or(BitcaskError::Decryption)?;
+            config.open(key, value).ok_or(BitcaskError::Decryption)?
+        } else {
+            value.to_vec()
+        };
+        *buf = decode_value(flags, value).ok_or(corrupt)?;
+        Ok(())
+    }
+
+    // Compresses `value`, then encrypts it if a key is configured. The
+    // record's key is bound into the tag, so values can't be swapped between
+    // keys unnoticed.
+    fn encode(&self, key: &[u8], value: Vec<u8>) -> Result<(u8, Vec<u8>), BitcaskError> {
+        let (flags, value) = encode_value(self.compression, value);
+        Ok(match &self.encryption {
+            Some(config) => (flags | FLAG_ENCRYPTED, config.seal(key, &value)?),
+            None => (flags, value),
+        })
+    }
+
+    /// Writes `value` under `key`, returning the value it replaced, if any.
+    /// An old value that fails to read back comes out as `None`.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, BitcaskError> {
+        self.put_record(key, value, 0, false)
+    }
+
+    /// Like `put`, but the value reads as absent once `ttl` has elapsed.
+    pub fn put_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<Option<Vec<u8>>, BitcaskError> {
+        // A TTL past what the clock can count never expires in practice.
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.put_record(key, value, self.now().saturating_add(ttl), false)
+    }
+
+    // Appends a record for `key`: a value, or a delete if `tombstone` is set.
+    fn put_record(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expiry: u64,
+        tombstone: bool,
+    ) -> Result<Option<Vec<u8>>, BitcaskError> {
+        self.check_writable()?;
+        Self::check_key(&key)?;
+        self.check_value_size(&value)?;
+        // A delete has no use for the old value, and one that can't be read
+        // back (under another encryption key, say) mustn't stop the write.
+        let old_value = match self.lookup(&key)? {
+            Some(_) if tombstone => None,
+            Some(kd) if kd.is_live(self.now()) => self.read_value(&key, kd).ok(),
+            Some(_) => None,
+            None => {
+                self.check_room(1)?;
+                None
+            }
+        };
+        let key_size = key.len() as u64;
+        let event = self.observer.is_some().then(|| {
+            if tombstone {
+                Event::Delete { key: key.clone() }
+            } else {
+                Event::Put {
+                    key: key.clone(),
+                    value: value.clone(),
+                }
+            }
+        });
+        let (flags, value) = if tombstone {
+            (FLAG_DELETE, value)
+        } else {
+            self.encode(&key, value)?
+        };
+        let value_size = value.len() as u64;
+        // Serialized straight from the borrowed key and value; the owned key
+        // then moves into the key dir.
+        let timestamp = self.now();
+        let crc =
+            DataFileEntry::checksum(timestamp, expiry, flags, key_size, value_size, &key, &value);
+        let record = record_bytes(crc, timestamp, expiry, flags, &key, &value);
+        let mut padding = self.padding(self.writer_pos);
+        if self.writer_pos > FILE_HEADER_SIZE
+            && self.writer_pos + (padding.len() + record.len()) as u64 > self.max_file_size
+        {
+            self.rotate()?;
+            padding = self.padding(self.writer_pos);
+        }
+        //  FORMAT: CRC + TMSTMP + EXPIRY + FLAGS + KEY_SIZE + VALUE_SIZE + KEY
+        let value_pos = self.writer_pos + padding.len() as u64 + HEADER_SIZE + key_size;
+        // One write, so a failure can't leave the padding behind alone.
+        let data = if padding.is_empty() {
+            record
+        } else {
+            [padding, record].concat()
+        };
+        let kd_value = KeyDir {
+            file_id: self.active_file_id,
+            value_size,
+            value_pos,
+            timestamp,
+            expiry,
+            tombstone,
+        };
+        self.append(&data)?;
+        // FORMAT: CRC + TMSTMP + EXPIRY + FLAGS + KEY_SIZE + VALUE_SIZE + KEY + VALUE
+        self.writer_pos += data.len() as u64;
+        self.index_record(key, kd_value)?;
+        if tombstone {
+            bump(&self.counters.deletes, 1);
+        } else {
+            bump(&self.counters.puts, 1);
+        }
+        self.unsynced_writes += 1;
+        let sync = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced_writes >= n,
+            SyncPolicy::Interval(_) => {
+                self.hand_to_flusher()?;
+                false
+            }
+        };
+        if sync {
+            self.sync()?;
+        }
+        self.notify(event);
+        self.maybe_auto_merge();
+        Ok(old_value)
+    }
+
+    /// Writes all `entries` with a single write and a single fsync. If the
+    /// write fails none of the entries become visible.
+    pub fn put_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        let mut new_keys = HashSet::new();
+        for (key, value) in &entries {
+            Self::check_key(key)?;
+            self.check_value_size(value)?;
+            if self.lookup(key)?.is_none() {
+                new_keys.insert(key.as_slice());
+            }
+        }
+        self.check_room(new_keys.len())?;
+        let mut records = Vec::with_capacity(entries.len());
+        let mut events = Vec::new();
+        for (key, value) in entries {
+            if self.observer.is_some() {
+                events.push(Event::Put {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+            let (flags, value) = self.encode(&key, value)?;
+            let entry = DataFileEntry::with_timestamp(key, value, self.now(), 0, flags);
+            records.push((0, entry));
+        }
+        let mut data = self.pack_records(self.writer_pos, &mut records);
+        if self.writer_pos > FILE_HEADER_SIZE
+            && self.writer_pos + data.len() as u64 > self.max_file_size
+        {
+            self.rotate()?;
+            // Padding depends on where the batch starts.
+            data = self.pack_records(self.writer_pos, &mut records);
+        }
+        self.append(&data)?;
+        bump(&self.counters.puts, records.len() as u64);
+        for (record_pos, entry) in records {
+            let kd_value = KeyDir {
+                file_id: self.active_file_id,
+                value_size: entry.value_size,
+                value_pos: self.writer_pos + record_pos + HEADER_SIZE + entry.key_size,
+                timestamp: entry.timestamp,
+                expiry: entry.expiry,
+                tombstone: false,
+            };
+            self.index_record(entry.key, kd_value)?;
+        }
+        self.writer_pos += data.len() as u64;
+        self.sync()?;
+        for event in events {
+            self.notify(Some(event));
+        }
+        self.maybe_auto_merge();
+        Ok(())
+    }
+
+    // Merges once dead bytes pass `auto_merge_threshold`. The write that
+    // triggered it has already succeeded, so a failed merge isn't its error;
+    // the old files are all still there and a later write tries again. The
+    // failure is kept for `take_auto_merge_error` instead.
+    fn maybe_auto_merge(&mut self) {
+        if self.dead_ratio() > self.auto_merge_threshold {
+            if let Err(e) = self.merge() {
+                self.failed_auto_merges += 1;
+                self.auto_merge_error = Some(e);
+            }
+        }
+    }
+
+    /// Returns why the latest auto-merge failed, if one has since the last
+    /// call. `stats` counts every failure.
+    pub fn take_auto_merge_error(&mut self) -> Option<BitcaskError> {
+        self.auto_merge_error.take()
+    }
+
+    // Serializes `records` for appending at `pos`, padded as the alignment
+    // asks, and sets each one's offset within the result.
+    fn pack_records(&self, pos: u64, records: &mut [(u64, DataFileEntry)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (record_pos, entry) in records {
+            data.extend_from_slice(&self.padding(pos + data.len() as u64));
+            *record_pos = data.len() as u64;
+            data.extend_from_slice(&entry.to_bytes());
+        }
+        data
+    }
+
+    // The padding record to write at `pos` so the next record is aligned.
+    fn padding(&self, pos: u64) -> Vec<u8> {
+        padding_record(padding_len(self.record_alignment, pos))
+    }
+
+    // The space a record of `size` bytes takes up on disk, counting the
+    // padding that lines up the record after it.
+    fn padded_size(&self, size: u64) -> u64 {
+        size + padding_len(self.record_alignment, size)
+    }
+
+    // Bytes at the start of every data file that hold no records.
+    fn file_overhead(&self) -> u64 {
+        FILE_HEADER_SIZE + padding_len(self.record_alignment, FILE_HEADER_SIZE)
+    }
+
+    /// Calls `f` with every put and delete once it has been written and
+    /// synced as far as the sync policy asks, for keeping things like
+    /// secondary indexes in step with the store. Replaces any earlier
+    /// observer.
+    pub fn set_observer(&mut self, f: Box<dyn FnMut(Event) + Send>) {
+        self.observer = Some(Observer(Mutex::new(f)));
+    }
+
+    fn notify(&mut self, event: Option<Event>) {
+        if let (Some(observer), Some(event)) = (&mut self.observer, event) {
+            let f = observer.0.get_mut().unwrap_or_else(PoisonError::into_inner);
+            f(event);
+        }
+    }
+
+    // Points `key` at a freshly written record and updates the byte counts.
+    fn index_record(&mut self, key: Vec<u8>, kd: KeyDir) -> Result<(), BitcaskError> {
+        let record_size = self.padded_size(HEADER_SIZE + key.len() as u64 + kd.value_size);
+        self.total_bytes += record_size;
+        // A delete was counted dead when it was written.
+        if let Some(old) = self.lookup(&key)?.filter(|old| !old.tombstone) {
+            self.dead_bytes += self.padded_size(HEADER_SIZE + key.len() as u64 + old.value_size);
+        }
+        if kd.tombstone {
+            self.dead_bytes += record_size;
+        }
+        if let Some(keys) = &mut self.ordered_keys {
+            if kd.tombstone {
+                keys.remove(&key);
+            } else {
+                keys.insert(key.clone());
+            }
+        }
+        if kd.tombstone && self.drop_deleted_keys {
+            return self.index_remove(&key);
+        }
+        self.index_insert(key, kd)
+    }
+
+    // Finds the key dir entry for `key`. In hashed key mode that takes a
+    // disk read, since another key may share the hash.
+    fn lookup(&self, key: &[u8]) -> Result<Option<&KeyDir>, BitcaskError> {
+        let hashed = match &self.hashed {
+            Some(hashed) => hashed,
+            None => return Ok(self.key_dir.get(key)),
+        };
+        if let Some(kd) = hashed.collided.get(key) {
+            return Ok(Some(kd));
+        }
+        match hashed.slots.get(&(hashed.hasher)(key)) {
+            Some(entry) if self.slot_holds(entry, key)? => Ok(Some(&entry.kd)),
+            _ => Ok(None),
+        }
+    }
+
+    fn index_insert(&mut self, key: Vec<u8>, kd: KeyDir) -> Result<(), BitcaskError> {
+        let hashed = match &self.hashed {
+            Some(hashed) => hashed,
+            None => {
+                self.key_dir.insert(key, kd);
+                return Ok(());
+            }
+        };
+        let hash = (hashed.hasher)(&key);
+        let slotted = !hashed.collided.contains_key(&key)
+            && match hashed.slots.get(&hash) {
+                Some(entry) => self.slot_holds(entry, &key)?,
+                None => true,
+            };
+        let hashed = self.hashed.as_mut().unwrap();
+        if slotted {
+            let key_size = key.len() as u32;
+            hashed.slots.insert(hash, HashedEntry { kd, key_size });
+        } else {
+            hashed.collided.insert(key, kd);
+        }
+        Ok(())
+    }
+
+    fn index_remove(&mut self, key: &[u8]) -> Result<(), BitcaskError> {
+        let hashed = match &self.hashed {
+            Some(hashed) => hashed,
+            None => {
+                self.key_dir.remove(key);
+                return Ok(());
+            }
+        };
+        let hash = (hashed.hasher)(key);
+        let slotted = !hashed.collided.contains_key(key)
+            && match hashed.slots.get(&hash) {
+                Some(entry) => self.slot_holds(entry, key)?,
+                None => false,
+            };
+        let hashed = self.hashed.as_mut().unwrap();
+        if slotted {
+            hashed.slots.remove(&hash);
+        } else {
+            hashed.collided.remove(key);
+        }
+        Ok(())
+    }
+
+    // Whether the record `entry` points at is for `key`.
+    fn slot_holds(&self, entry: &HashedEntry, key: &[u8]) -> Result<bool, BitcaskError> {
+        if entry.key_size as usize != key.len() {
+            return Ok(false);
+        }
+        let corrupt = || BitcaskError::CorruptRecord {
+            file_id: entry.kd.file_id,
+            value_pos: entry.kd.value_pos,
+        };
+        let key_pos = entry
+            .kd
+            .value_pos
+            .checked_sub(key.len() as u64)
+            .filter(|pos| *pos >= FILE_HEADER_SIZE + HEADER_SIZE)
+            .ok_or_else(corrupt)?;
+        let stored_key = self.read_bytes(entry.kd.file_id, key_pos, key.len())?;
+        Ok(stored_key == key)
+    }
+
+    // Reads `len` bytes at `pos` in data file `file_id`, taking any that
+    // haven't been written out yet from the write buffer.
+    fn read_bytes(&self, file_id: u64, pos: u64, len: usize) -> Result<Vec<u8>, BitcaskError> {
+        let mut buf = vec![0u8; len];
+        let mut on_disk = len;
+        if let Some(writer) = &self.active_file {
+            let buffered = writer.buffer();
+            let flushed_pos = self.writer_pos - buffered.len() as u64;
+            let end = pos + len as u64;
+            if file_id == self.active_file_id && end > flushed_pos {
+                let from = pos.max(flushed_pos);
+                let range = (from - flushed_pos) as usize..(end - flushed_pos) as usize;
+                let corrupt = BitcaskError::CorruptRecord {
+                    file_id,
+                    value_pos: pos,
+                };
+                on_disk = (from - pos) as usize;
+                buf[on_disk..].copy_from_slice(buffered.get(range).ok_or(corrupt)?);
+            }
+        }
+        if on_disk > 0 {
+            let data_file = self.read_handle(file_id)?;
+            read_exact_at(&data_file, &mut buf[..on_disk], pos)?;
+        }
+        Ok(buf)
+    }
+
+    /// Reads the `value_size` bytes stored at `value_pos` in data file
+    /// `file_id`, wherever `get_entry` said a value lives, without going
+    /// through the key dir. They come back exactly as stored: compressed or
+    /// encrypted values stay that way, and nothing is checksummed.
+    pub fn read_at(
+        &self,
+        file_id: u64,
+        value_pos: u64,
+        value_size: u64,
+    ) -> Result<Vec<u8>, BitcaskError> {
+        let file_len = match &self.active_file {
+            Some(_) if file_id == self.active_file_id => self.writer_pos,
+            _ => self.read_handle(file_id)?.metadata()?.len(),
+        };
+        let in_bounds = value_pos >= FILE_HEADER_SIZE
+            && value_pos
+                .checked_add(value_size)
+                .is_some_and(|end| end <= file_len);
+        if !in_bounds {
+            return Err(BitcaskError::CorruptRecord { file_id, value_pos });
+        }
+        self.read_bytes(file_id, value_pos, value_size as usize)
+    }
+
+    // Every key dir entry with the size of its key, in either mode.
+    fn index_entries(&self) -> impl Iterator<Item = (u64, &KeyDir)> + '_ {
+        let hashed = self.hashed.iter().flat_map(|hashed| {
+            let slots = hashed
+                .slots
+                .values()
+                .map(|entry| (entry.key_size as u64, &entry.kd));
+            let collided = hashed
+                .collided
+                .iter()
+                .map(|(key, kd)| (key.len() as u64, kd));
+            slots.chain(collided)
+        });
+        self.key_dir
+            .iter()
+            .map(|(key, kd)| (key.len() as u64, kd))
+            .chain(hashed)
+    }
+
+    // In hashed key mode, moves a freshly built key dir into its hashed form.
+    fn hash_key_dir(&mut self) {
+        if let Some(hashed) = &mut self.hashed {
+            hashed.fill(std::mem::take(&mut self.key_dir));
+        }
+    }
+
+    // Merging works on whole keys, so in hashed key mode the key dir is
+    // spelled out in full for the length of `f` and hashed again after.
+    fn with_full_keys<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, BitcaskError>,
+    ) -> Result<T, BitcaskError> {
+        let hashed = match &self.hashed {
+            Some(hashed) => hashed,
+            None => return f(self),
+        };
+        let mut key_dir = hashed.collided.clone();
+        for entry in hashed.slots.values() {
+            let key_pos = entry.kd.value_pos.saturating_sub(entry.key_size as u64);
+            let key = self.read_bytes(entry.kd.file_id, key_pos, entry.key_size as usize)?;
+            key_dir.insert(key, entry.kd.clone());
+        }
+        let mut hashed = self.hashed.take().unwrap();
+        hashed.clear();
+        self.key_dir = key_dir;
+        let result = f(self);
+        hashed.fill(std::mem::take(&mut self.key_dir));
+        self.hashed = Some(hashed);
+        result
+    }
+
+    /// Returns the live value for `key`, or stores and returns what `f`
+    /// makes if there isn't one. `f` only runs on a miss.
+    pub fn get_or_insert_with<F: FnOnce() -> Vec<u8>>(
+        &mut self,
+        key: &[u8],
+        f: F,
+    ) -> Result<Vec<u8>, BitcaskError> {
+        match self.get(key) {
+            Err(BitcaskError::KeyNotFound) => {}
+            result => return result,
+        }
+        let value = f();
+        self.put(key.to_vec(), value.clone())?;
+        Ok(value)
+    }
+
+    /// Replaces the value for `key` with whatever `f` makes of it, getting
+    /// `None` for a missing key. Returning `None` deletes the key.
+    pub fn modify<F: FnOnce(Option<Vec<u8>>) -> Option<Vec<u8>>>(
+        &mut self,
+        key: &[u8],
+        f: F,
+    ) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        let current = if self.contains_key(key) {
+            Some(self.get(key)?)
+        } else {
+            None
+        };
+        match f(current) {
+            Some(value) => {
+                self.put(key.to_vec(), value)?;
+            }
+            None => {
+                self.delete(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `new` under `key` only if its live value is `expected`, with
+    /// `None` meaning it must be absent. Returns whether the write happened.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<bool, BitcaskError> {
+        self.check_writable()?;
+        let current = if self.contains_key(key) {
+            Some(self.get(key)?)
+        } else {
+            None
+        };
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        self.put(key.to_vec(), new)?;
+        Ok(true)
+    }
+
+    /// Deletes `key`, returning whether it had a live value. Nothing is
+    /// written when there was nothing to delete.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, BitcaskError> {
+        self.check_writable()?;
+        Self::check_key(key)?;
+        if !self.contains_key(key) {
+            return Ok(false);
+        }
+        self.put_record(key.to_vec(), Vec::new(), 0, true)?;
+        Ok(true)
+    }
+
+    /// Returns true if `key` has a live value. Only hashed key mode reads
+    /// the data files for this.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.lookup(key)
+            .ok()
+            .flatten()
+            .is_some_and(|kd| kd.is_live(self.now()))
+    }
+
+    /// Number of live keys. Deleted and expired keys that are still waiting
+    /// for a merge aren't counted.
+    pub fn len(&self) -> usize {
+        let now = self.now();
+        self.index_entries()
+            .filter(|(_, kd)| kd.is_live(now))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Lazily yields every live key/value pair, reading values on demand.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), BitcaskError>> + '_ {
+        let now = self.now();
+        self.key_dir
+            .iter()
+            .filter(move |(_, kd)| kd.is_live(now))
+            .map(|(key, kd)| Ok((key.clone(), self.read_value(key, kd)?)))
+    }
+
+    /// The live keys last written after the timestamp `ts`, in milliseconds
+    /// since the epoch, for pulling just what changed since a watermark.
+    /// Keys deleted since then aren't among them.
+    pub fn keys_since(&self, ts: u64) -> impl Iterator<Item = &[u8]> + '_ {
+        let now = self.now();
+        self.key_dir
+            .iter()
+            .filter(move |(_, kd)| kd.timestamp > ts && kd.is_live(now))
+            .map(|(key, _)| key.as_slice())
+    }
+
+    /// Like `iter`, but yields only the values, without copying any keys.
+    pub fn values(&self) -> impl Iterator<Item = Result<Vec<u8>, BitcaskError>> + '_ {
+        let now = self.now();
+        self.key_dir
+            .iter()
+            .filter(move |(_, kd)| kd.is_live(now))
+            .map(|(key, kd)| self.read_value(key, kd))
+    }
+
+    /// Replays the log: every record still on disk or in the write buffer,
+    /// overwritten values and deletes included, oldest data file first and
+    /// in the order each file was written. Merged files only hold what was
+    /// live when they were written, plus any versions `keep_versions` kept.
+    pub fn scan_log(&self) -> impl Iterator<Item = Result<LogRecord, BitcaskError>> + '_ {
+        let (file_ids, listed) = match self.data_file_ids() {
+            Ok(file_ids) => (file_ids, None),
+            Err(e) => (Vec::new(), Some(Err(e))),
+        };
+        let records = file_ids.into_iter().flat_map(move |file_id| {
+            let (entries, scanned) = match self.log_entries(file_id) {
+                Ok(entries) => (entries, None),
+                Err(e) => (Vec::new(), Some(Err(e))),
+            };
+            let records = entries.into_iter().map(move |(key, kd)| {
+                let value = if kd.tombstone {
+                    Vec::new()
+                } else {
+                    self.read_value(&key, &kd)?
+                };
+                Ok(LogRecord {
+                    key,
+                    value,
+                    timestamp: kd.timestamp,
+                    tombstone: kd.tombstone,
+                })
+            });
+            scanned.into_iter().chain(records)
+        });
+        listed.into_iter().chain(records)
+    }
+
+    fn data_file_ids(&self) -> Result<Vec<u64>, BitcaskError> {
+        let mut file_ids = Vec::new();
+        for filepath in self.layout.entries(&self.data_path)? {
+            if let Some(file_id) = store_file_id(&filepath) {
+                if filepath.extension().is_some_and(|ext| ext == "dat") {
+                    file_ids.push(file_id);
+                }
+            }
+        }
+        file_ids.sort_unstable();
+        Ok(file_ids)
+    }
+
+    // Indexes the records of one data file in write order, including those
+    // of the active file that are still buffered.
+    fn log_entries(&self, file_id: u64) -> Result<FileEntries, BitcaskError> {
+        let filepath = self.layout.path(&self.data_path, file_id, "dat");
+        let mut entries = scan_data_file(&filepath, file_id, self.recovery_mode)?;
+        let buffered = match &self.active_file {
+            Some(writer) if file_id == self.active_file_id => writer.buffer(),
+            _ => return Ok(entries),
+        };
+        let mut reader = buffered;
+        let mut pos = self.writer_pos - buffered.len() as u64;
+        while pos < self.writer_pos {
+            let scanned = scan_record(
+                &mut reader,
+                file_id,
+                FORMAT_VERSION,
+                &mut pos,
+                self.writer_pos,
+            )?;
+            match scanned {
+                Scanned::Record { key, kd } => entries.push((key, kd)),
+                Scanned::Padding => {}
+                // Appends go into the buffer whole, so this is memory gone
+                // bad, not a torn write.
+                Scanned::Truncated | Scanned::Corrupt { .. } => {
+                    return Err(BitcaskError::CorruptRecord {
+                        file_id,
+                        value_pos: pos,
+                    })
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Yields every live key starting with `prefix`, in no particular order.
+    pub fn prefix_scan<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let now = self.now();
+        self.key_dir
+            .iter()
+            .filter(move |(key, kd)| key.starts_with(prefix) && kd.is_live(now))
+            .map(|(key, _)| key.as_slice())
+    }
+
+    /// Yields the live pairs with keys in `start..end`, in key order. Uses the
+    /// ordered index when it's enabled and sorts the matching keys otherwise.
+    pub fn range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), BitcaskError>> + '_ {
+        let keys: Vec<&Vec<u8>> = match &self.ordered_keys {
+            Some(keys) if start < end => keys
+                .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                .collect(),
+            Some(_) => Vec::new(),
+            None => {
+                let mut keys: Vec<&Vec<u8>> = self
+                    .key_dir
+                    .keys()
+                    .filter(|key| start <= key.as_slice() && key.as_slice() < end)
+                    .collect();
+                keys.sort();
+                keys
+            }
+        };
+        let now = self.now();
+        keys.into_iter().filter_map(move |key| {
+            let kd = self.key_dir.get(key)?;
+            kd.is_live(now)
+                .then(|| Ok((key.clone(), self.read_value(key, kd)?)))
+        })
+    }
+
+    /// Returns up to `limit` live keys in key order, starting after `after`.
+    /// Pass the last key of one page as `after` to get the next.
+    pub fn keys_page(&self, after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        let now = self.now();
+        let keys: Vec<&Vec<u8>> = match &self.ordered_keys {
+            Some(keys) => {
+                let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+                keys.range::<[u8], _>((lower, Bound::Unbounded))
+                    .filter(|key| self.key_dir.get(*key).is_some_and(|kd| kd.is_live(now)))
+                    .take(limit)
+                    .collect()
+            }
+            None => {
+                let mut keys: Vec<&Vec<u8>> = self
+                    .key_dir
+                    .iter()
+                    .filter(|(key, kd)| {
+                        after.is_none_or(|after| key.as_slice() > after) && kd.is_live(now)
+                    })
+                    .map(|(key, _)| key)
+                    .collect();
+                keys.sort();
+                keys.truncate(limit);
+                keys
+            }
+        };
+        keys.into_iter().cloned().collect()
+    }
+
+    /// The live keys, in no particular order. Deleted and expired keys
+    /// still held in the key dir are left out.
+    pub fn list_keys(&self) -> Option<Vec<&Vec<u8>>> {
+        let now = self.now();
+        Some(
+            self.key_dir
+                .iter()
+                .filter(|(_, kd)| kd.is_live(now))
+                .map(|(key, _)| key)
+                .collect(),
+        )
+    }
+
+    /// Every entry of the key dir as `(key, file_id, value_size, value_pos,
+    /// timestamp)`, sorted by key, for checking the index against the files
+    /// on disk. Deletes still held in the key dir are included. In hashed
+    /// key mode keys are read back from the data files, and an entry whose
+    /// key can't be read is left out.
+    pub fn debug_keydir(&self) -> Vec<(Vec<u8>, u64, u64, u64, u64)> {
+        let mut entries: Vec<_> = self
+            .key_dir
+            .iter()
+            .map(|(key, kd)| (key.clone(), kd))
+            .collect();
+        if let Some(hashed) = &self.hashed {
+            for entry in hashed.slots.values() {
+                let key_pos = entry.kd.value_pos.saturating_sub(entry.key_size as u64);
+                if let Ok(key) = self.read_bytes(entry.kd.file_id, key_pos, entry.key_size as usize)
+                {
+                    entries.push((key, &entry.kd));
+                }
+            }
+            entries.extend(hashed.collided.iter().map(|(key, kd)| (key.clone(), kd)));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .map(|(key, kd)| (key, kd.file_id, kd.value_size, kd.value_pos, kd.timestamp))
+            .collect()
+    }
+
+    /// Stores `value` under the metadata key `key`, for things like a schema
+    /// version. Metadata is kept in a file of its own, so it's never among
+    /// the keys and merges leave it alone. It's written through at once and
+    /// isn't encrypted.
+    pub fn set_meta(&mut self, key: &str, value: &[u8]) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        let mut meta = self.meta.clone();
+        meta.insert(key.to_string(), value.to_vec());
+        write_meta(&self.data_path, &meta)?;
+        self.meta = meta;
+        Ok(())
+    }
+
+    pub fn get_meta(&self, key: &str) -> Option<&[u8]> {
+        self.meta.get(key).map(Vec::as_slice)
+    }
+
+    /// Writes a `.hint` file for the sealed data file `file_id` so reopening
+    /// doesn't have to scan it.
+    pub fn generate_hint(&self, file_id: u64) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        if file_id == self.active_file_id {
+            return Err(BitcaskError::ActiveFile);
+        }
+        self.write_hint(file_id)
+    }
+
+    // Writes the hint for `file_id` from a scan of the data file. The key dir
+    // could say much the same, but it doesn't keep deletes when
+    // `drop_deleted_keys` is set, and a freshly written file reads back from
+    // the page cache anyway. The hint is only renamed into place once it's
+    // complete.
+    fn write_hint(&self, file_id: u64) -> Result<(), BitcaskError> {
+        let filepath = self.layout.path(&self.data_path, file_id, "dat");
+        let entries = scan_data_file(&filepath, file_id, self.recovery_mode)?;
+        let hint_filepath = filepath.with_extension("hint");
+        let temp_filepath = filepath.with_extension(format!("hint.{}", MERGING_EXT));
+        let mut hint_file = BufWriter::new(fs::File::create(&temp_filepath)?);
+        hint_file.write_all(&file_header(HINT_MAGIC))?;
+        let mut hint_file = HintWriter::new(hint_file);
+        for (key, kd) in entries {
+            let hint_entry = HintFileEntry {
+                timestamp: kd.timestamp,
+                expiry: kd.expiry,
+                key_size: key.len() as u64,
+                value_size: kd.value_size,
+                value_pos: kd.value_pos,
+                key,
+            };
+            hint_file.write(&hint_entry)?;
+        }
+        let hint_file = hint_file
+            .finish()?
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        hint_file.sync_all()?;
+        fs::rename(temp_filepath, hint_filepath)?;
+        sync_dir(&self.layout.dir_of(&self.data_path, file_id))?;
+        Ok(())
+    }
+
+    /// Regenerates the hint of every sealed data file from the data itself,
+    /// replacing any hint that is already there.
+    pub fn rebuild_all_hints(&self) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        for filepath in self.layout.entries(&self.data_path)? {
+            if let Some(file_id) = store_file_id(&filepath) {
+                if file_id != self.active_file_id
+                    && filepath.extension().is_some_and(|ext| ext == "dat")
+                {
+                    self.generate_hint(file_id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes every live pair to data and hint file pairs in `dirpath`,
+    // under temporary names until the result is published. With a `source`,
+    // only the pairs whose newest record is in that data file are written.
+    fn write_merged(
+        &self,
+        dirpath: &Path,
+        source: Option<u64>,
+    ) -> Result<PreparedMerge, BitcaskError> {
+        let mut prepared = PreparedMerge {
+            dirpath: dirpath.to_path_buf(),
+            layout: self.layout,
+            file_ids: Vec::new(),
+            key_dir: HashMap::new(),
+            active_file_id: self.active_file_id,
+            writer_pos: self.writer_pos,
+            published: false,
+        };
+        let mut outputs = vec![self.open_merge_output(dirpath, &mut prepared)?];
+        let mut write_pos = FILE_HEADER_SIZE;
+        let mut merged = HashMap::new();
+        let now = self.now();
+        let kept = match source {
+            None => self.kept_versions()?,
+            // A step's output gets an id newer than files holding later
+            // versions, so it can't carry older ones.
+            Some(_) => HashMap::new(),
+        };
+        // The key dir always points at the newest record for each key.
+        for (key, newest) in &self.key_dir {
+            if !newest.is_live(now) || source.is_some_and(|id| newest.file_id != id) {
+                continue;
+            }
+            // Older versions go first, so the newest still wins on reopen.
+            let older = kept.get(&(newest.file_id, newest.value_pos));
+            for kd in older.into_iter().flatten().chain([newest]) {
+                let value = self.read_value(key, kd)?;
+                let key_len = key.len() as u64;
+                let expiry = kd.expiry;
+                let (flags, value) = self.encode(key, value)?;
+                let entry =
+                    DataFileEntry::with_timestamp(key.to_vec(), value, kd.timestamp, expiry, flags);
+                let data = entry.to_bytes();
+                let mut padding = self.padding(write_pos);
+                // Output files are capped like the active file is.
+                if write_pos > FILE_HEADER_SIZE
+                    && write_pos + (padding.len() + data.len()) as u64 > self.max_file_size
+                {
+                    outputs.push(self.open_merge_output(dirpath, &mut prepared)?);
+                    write_pos = FILE_HEADER_SIZE;
+                    padding = self.padding(write_pos);
+                }
+                let (file_id, merge_file, hint_file) = outputs.last_mut().unwrap();
+                let file_id = *file_id;
+                merge_file.write_all(&padding)?;
+                write_pos += padding.len() as u64;
+                merge_file.write_all(&data)?;
+                let value_pos = write_pos + HEADER_SIZE + key_len;
+
+                let hint_entry = HintFileEntry {
+                    timestamp: entry.timestamp,
+                    expiry: entry.expiry,
+                    key_size: entry.key_size,
+                    value_size: entry.value_size,
+                    value_pos,
+                    key: entry.key,
+                };
+                hint_file.write(&hint_entry)?;
+                merged.insert(
+                    hint_entry.key,
+                    KeyDir {
+                        file_id,
+                        value_size: hint_entry.value_size,
+                        value_pos,
+                        timestamp: hint_entry.timestamp,
+                        expiry: hint_entry.expiry,
+                        tombstone: false,
+                    },
+                );
+
+                write_pos += data.len() as u64;
+            }
+        }
+        // Make the merged output durable before any of the old files go away.
+        for (_, merge_file, hint_file) in outputs {
+            merge_file.sync_all()?;
+            hint_file.finish()?.sync_all()?;
+        }
+        prepared.key_dir = merged;
+        Ok(prepared)
+    }
+
+    // With `keep_versions` above 1, the older values a full merge keeps for
+    // each live key, oldest first, by where the key's newest record is.
+    // Values since deleted still count; a key that's deleted now keeps none.
+    fn kept_versions(&self) -> Result<KeptVersions, BitcaskError> {
+        let mut kept = KeptVersions::new();
+        if self.keep_versions <= 1 {
+            return Ok(kept);
+        }
+        let now = self.now();
+        for file_id in self.data_file_ids()? {
+            for (key, kd) in self.log_entries(file_id)? {
+                if kd.tombstone || !kd.is_live(now) {
+                    continue;
+                }
+                let newest = match self.lookup(&key)? {
+                    Some(newest) if newest.is_live(now) => (newest.file_id, newest.value_pos),
+                    _ => continue,
+                };
+                if newest == (kd.file_id, kd.value_pos) {
+                    continue;
+                }
+                let versions = kept.entry(newest).or_default();
+                if versions.len() == self.keep_versions - 1 {
+                    versions.pop_front();
+                }
+                versions.push_back(kd);
+            }
+        }
+        Ok(kept)
+    }
+
+    // Starts a new data and hint file pair for merge output. They're written
+    // under temporary names and only renamed into place once complete, so a
+    // crash mid-merge leaves nothing a reopen reads.
+    fn open_merge_output(
+        &self,
+        dirpath: &Path,
+        prepared: &mut PreparedMerge,
+    ) -> Result<(u64, fs::File, HintWriter<fs::File>), BitcaskError> {
+        let file_id = gen_file_id(self.layout, dirpath, self.now())?;
+        prepared.file_ids.push(file_id);
+        let open = |ext: &str, magic: &[u8; 4]| -> Result<fs::File, BitcaskError> {
+            let filepath = self
+                .layout
+                .path(dirpath, file_id, &format!("{}.{}", ext, MERGING_EXT));
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(filepath)?;
+            file.write_all(&file_header(magic))?;
+            Ok(file)
+        };
+        let hint_file = HintWriter::new(open("hint", HINT_MAGIC)?);
+        Ok((file_id, open("dat", DATA_MAGIC)?, hint_file))
+    }
+
+    /// Captures the store as it is now into `dest`. Sealed files never change,
+    /// so they're hard-linked where possible; the active file is flushed and
+    /// copied, since it keeps growing.
+    pub fn snapshot<P: AsRef<Path>>(&mut self, dest: P) -> Result<(), BitcaskError> {
+        let dest: &Path = dest.as_ref();
+        self.sync()?;
+        fs::create_dir_all(dest)?;
+        let _lock_file = lock_dir(dest)?;
+        self.layout.create_dirs(dest)?;
+        for filepath in self.layout.entries(&self.data_path)? {
+            let file_id = match store_file_id(&filepath) {
+                Some(id) => id,
+                None => continue,
+            };
+            let target = dest.join(filepath.strip_prefix(&self.data_path).unwrap());
+            if file_id == self.active_file_id || fs::hard_link(&filepath, &target).is_err() {
+                fs::copy(&filepath, &target)?;
+                fs::File::open(&target)?.sync_all()?;
+            }
+        }
+        if !self.meta.is_empty() {
+            write_meta(dest, &self.meta)?;
+        }
+        for dir in self.layout.dirs(dest) {
+            sync_dir(&dir)?;
+        }
+        sync_dir(dest)?;
+        Ok(())
+    }
+
+    /// Writes a compacted copy of every live pair into `dest` as data and
+    /// hint file pairs, leaving this store untouched.
+    pub fn merge_into<P: AsRef<Path>>(&self, dest: P) -> Result<(), BitcaskError> {
+        let dest: &Path = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        // Keep any writer out of `dest` while the copy goes in.
+        let _lock_file = lock_dir(dest)?;
+        if self.hashed.is_some() {
+            return Err(BitcaskError::HashedKeys);
+        }
+        self.layout.create_dirs(dest)?;
+        self.write_merged(dest, None)?.publish()?;
+        if !self.meta.is_empty() {
+            write_meta(dest, &self.meta)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites every live value into fresh files in the store's directory
+    /// and deletes the files they replace.
+    pub fn merge(&mut self) -> Result<(), BitcaskError> {
+        self.with_full_keys(|bitcask| {
+            let prepared = bitcask.prepare_merge()?;
+            bitcask.finish_merge(prepared)
+        })
+    }
+
+    /// Works out what `merge` would do right now without writing anything.
+    pub fn merge_dry_run(&self) -> Result<MergePlan, BitcaskError> {
+        let mut plan = MergePlan::default();
+        let mut total_bytes = 0;
+        for filepath in self.layout.entries(&self.data_path)? {
+            if let Some(file_id) = store_file_id(&filepath) {
+                if filepath.extension().is_some_and(|ext| ext == "dat") {
+                    plan.files_removed.push(file_id);
+                    total_bytes += fs::metadata(&filepath)?.len();
+                }
+            }
+        }
+        plan.files_removed.sort_unstable();
+        if let Some(writer) = &self.active_file {
+            total_bytes += writer.buffer().len() as u64;
+        }
+
+        // Lay the live records out the way `write_merged` does, in the same
+        // order and under the same size cap.
+        let now = self.now();
+        let kept = self.kept_versions()?;
+        let mut written = 0;
+        let mut write_pos = 0;
+        let records = self
+            .index_entries()
+            .filter(|(_, kd)| kd.is_live(now))
+            .flat_map(|(key_size, newest)| {
+                let older = kept.get(&(newest.file_id, newest.value_pos));
+                older
+                    .into_iter()
+                    .flatten()
+                    .chain([newest])
+                    .map(move |kd| (key_size, kd))
+            });
+        for (key_size, kd) in records {
+            let record_size = HEADER_SIZE + key_size + kd.value_size;
+            let mut padding = padding_len(self.record_alignment, write_pos);
+            if write_pos == 0
+                || (write_pos > FILE_HEADER_SIZE
+                    && write_pos + padding + record_size > self.max_file_size)
+            {
+                written += FILE_HEADER_SIZE;
+                write_pos = FILE_HEADER_SIZE;
+                padding = padding_len(self.record_alignment, write_pos);
+            }
+            write_pos += padding + record_size;
+            written += padding + record_size;
+            plan.live_records += 1;
+        }
+        // Plus the fresh active file the merge moves on to.
+        written += FILE_HEADER_SIZE;
+        plan.reclaimed_bytes = total_bytes.saturating_sub(written);
+        Ok(plan)
+    }
+
+    /// Does the slow part of a merge, writing compacted copies of every live
+    /// pair, while only reading the store. Other readers carry on as usual;
+    /// `commit_merge` then swaps the result in.
+    pub fn prepare_merge(&self) -> Result<PreparedMerge, BitcaskError> {
+        self.check_writable()?;
+        if self.hashed.is_some() {
+            return Err(BitcaskError::HashedKeys);
+        }
+        self.write_merged(&self.data_path, None)
+    }
+
+    /// Compacts a single sealed data file, oldest first, so that a merge can
+    /// be spread out over time. Returns whether files are left for later
+    /// steps; files sealed after a round starts wait for the next one.
+    pub fn merge_step(&mut self) -> Result<bool, BitcaskError> {
+        self.with_full_keys(Self::merge_next_file)
+    }
+
+    fn merge_next_file(&mut self) -> Result<bool, BitcaskError> {
+        self.check_writable()?;
+        if self.merge_queue.is_empty() {
+            for filepath in self.layout.entries(&self.data_path)? {
+                if let Some(file_id) = store_file_id(&filepath) {
+                    if file_id != self.active_file_id
+                        && filepath.extension().is_some_and(|ext| ext == "dat")
+                    {
+                        self.merge_queue.push(file_id);
+                    }
+                }
+            }
+            self.merge_queue.sort_unstable_by(|a, b| b.cmp(a));
+        }
+        let file_id = match self.merge_queue.pop() {
+            Some(file_id) => file_id,
+            None => return Ok(false),
+        };
+
+        let mut prepared = self.write_merged(&self.data_path, Some(file_id))?;
+        if !prepared.key_dir.is_empty() {
+            prepared.publish()?;
+            // The output's id is newer than the active file's, so later
+            // writes need a file after it to win on reopen.
+            let emptied = (self.writer_pos == FILE_HEADER_SIZE).then_some(self.active_file_id);
+            self.rotate()?;
+            if let Some(emptied) = emptied {
+                self.remove_data_file(emptied)?;
+            }
+            self.key_dir.extend(std::mem::take(&mut prepared.key_dir));
+        }
+        // Every older file is gone by now, so deletes recorded in this one
+        // have nothing left to shadow.
+        self.key_dir
+            .retain(|_, kd| !(kd.tombstone && kd.file_id == file_id));
+        self.remove_data_file(file_id)?;
+        self.recount_bytes()?;
+        Ok(!self.merge_queue.is_empty())
+    }
+
+    /// Rewrites the live values of the sealed data file `file_id` into the
+    /// active file, then deletes it and its hint. This reclaims one cold
+    /// file without a full merge. Deletes recorded in it are carried over
+    /// while older files could still hold the values they delete.
+    pub fn compact_file(&mut self, file_id: u64) -> Result<(), BitcaskError> {
+        self.with_full_keys(|bitcask| bitcask.compact_into_active(file_id))
+    }
+
+    fn compact_into_active(&mut self, file_id: u64) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        if file_id == self.active_file_id {
+            return Err(BitcaskError::ActiveFile);
+        }
+        let filepath = self.layout.path(&self.data_path, file_id, "dat");
+        let scanned = scan_data_file(&filepath, file_id, self.recovery_mode)?;
+        let shadows = self.data_file_ids()?.iter().any(|&id| id < file_id);
+        let now = self.now();
+        let mut records = Vec::new();
+        let mut dropped = Vec::new();
+        for (key, kd) in &self.key_dir {
+            if kd.file_id != file_id {
+                continue;
+            }
+            if kd.is_live(now) {
+                let (flags, value) = self.encode(key, self.read_value(key, kd)?)?;
+                let entry = DataFileEntry::with_timestamp(
+                    key.clone(),
+                    value,
+                    kd.timestamp,
+                    kd.expiry,
+                    flags,
+                );
+                records.push((0, entry));
+            } else if shadows {
+                let entry = DataFileEntry::with_timestamp(
+                    key.clone(),
+                    Vec::new(),
+                    kd.timestamp,
+                    0,
+                    FLAG_DELETE,
+                );
+                records.push((0, entry));
+            } else {
+                dropped.push(key.clone());
+            }
+        }
+        // With `drop_deleted_keys` the key dir forgets deletes, so the ones
+        // still standing come from the file itself.
+        if shadows && self.drop_deleted_keys {
+            let mut latest = HashMap::new();
+            for (key, kd) in scanned {
+                latest.insert(key, kd);
+            }
+            for (key, kd) in latest {
+                if kd.tombstone && !self.key_dir.contains_key(&key) {
+                    let entry = DataFileEntry::with_timestamp(
+                        key,
+                        Vec::new(),
+                        kd.timestamp,
+                        0,
+                        FLAG_DELETE,
+                    );
+                    records.push((0, entry));
+                }
+            }
+        }
+
+        let mut data = self.pack_records(self.writer_pos, &mut records);
+        if self.writer_pos > FILE_HEADER_SIZE
+            && self.writer_pos + data.len() as u64 > self.max_file_size
+        {
+            self.rotate()?;
+            data = self.pack_records(self.writer_pos, &mut records);
+        }
+        self.append(&data)?;
+        for (record_pos, entry) in records {
+            let kd = KeyDir {
+                file_id: self.active_file_id,
+                value_size: entry.value_size,
+                value_pos: self.writer_pos + record_pos + HEADER_SIZE + entry.key_size,
+                timestamp: entry.timestamp,
+                expiry: entry.expiry,
+                tombstone: entry.flags & FLAG_DELETE != 0,
+            };
+            self.index_record(entry.key, kd)?;
+        }
+        self.writer_pos += data.len() as u64;
+        // The copies have to be durable before the originals go away.
+        self.sync()?;
+        for key in dropped {
+            self.index_remove(&key)?;
+            if let Some(keys) = &mut self.ordered_keys {
+                keys.remove(&key);
+            }
+        }
+        self.merge_queue.retain(|&id| id != file_id);
+        self.remove_data_file(file_id)?;
+        self.recount_bytes()
+    }
+
+    // Deletes the data file `file_id` and its hint, if it has one.
+    fn remove_data_file(&mut self, file_id: u64) -> Result<(), BitcaskError> {
+        self.read_handles.get_mut().unwrap().remove(&file_id);
+        for ext in ["dat", "hint"] {
+            match fs::remove_file(self.layout.path(&self.data_path, file_id, ext)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        sync_dir(&self.layout.dir_of(&self.data_path, file_id))?;
+        Ok(())
+    }
+
+    /// Swaps in a merge from `prepare_merge` and deletes the files it
+    /// replaces. Anything written since it was prepared is kept.
+    pub fn commit_merge(&mut self, prepared: PreparedMerge) -> Result<(), BitcaskError> {
+        if self.hashed.is_some() {
+            return Err(BitcaskError::HashedKeys);
+        }
+        self.finish_merge(prepared)
+    }
+
+    fn finish_merge(&mut self, mut prepared: PreparedMerge) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        // The merge output got ids newer than every file it replaces, so a
+        // fresh active file goes after it. Whatever changed since the merge
+        // started is written again there so that it still wins on reopen.
+        self.rotate()?;
+        let mut key_dir = std::mem::take(&mut prepared.key_dir);
+        let mut changed: Vec<Vec<u8>> = self
+            .key_dir
+            .iter()
+            .filter(|(_, kd)| prepared.is_newer(kd))
+            .map(|(key, _)| key.clone())
+            .collect();
+        // Keys deleted and dropped from the key dir since.
+        changed.extend(
+            key_dir
+                .keys()
+                .filter(|key| !self.key_dir.contains_key(*key))
+                .cloned(),
+        );
+        let now = self.now();
+        let mut data = Vec::new();
+        for key in changed {
+            let entry = match self.key_dir.get(&key) {
+                Some(kd) if kd.is_live(now) => {
+                    let (flags, value) = self.encode(&key, self.read_value(&key, kd)?)?;
+                    DataFileEntry::with_timestamp(key, value, kd.timestamp, kd.expiry, flags)
+                }
+                // Only a key the output still holds needs its delete repeated.
+                _ if key_dir.remove(&key).is_some() => {
+                    DataFileEntry::with_timestamp(key, Vec::new(), now, 0, FLAG_DELETE)
+                }
+                _ => continue,
+            };
+            data.extend_from_slice(&self.padding(self.writer_pos + data.len() as u64));
+            let value_pos = self.writer_pos + data.len() as u64 + HEADER_SIZE + entry.key_size;
+            data.extend_from_slice(&entry.to_bytes());
+            if entry.flags & FLAG_DELETE == 0 {
+                let kd = KeyDir {
+                    file_id: self.active_file_id,
+                    value_size: entry.value_size,
+                    value_pos,
+                    timestamp: entry.timestamp,
+                    expiry: entry.expiry,
+                    tombstone: false,
+                };
+                key_dir.insert(entry.key, kd);
+            }
+        }
+        self.append(&data)?;
+        self.writer_pos += data.len() as u64;
+        self.sync()?;
+        // Until the output is published the handle reads only the old
+        // files, all still there, so failing up to here changes nothing a
+        // reader sees.
+        prepared.publish()?;
+
+        // The output now holds everything, so the handle switches over to it
+        // in one step before any old file goes. A failure past this point
+        // only leaves files behind for the next merge.
+        let merged_ids: HashSet<u64> = key_dir.values().map(|kd| kd.file_id).collect();
+        self.key_dir = key_dir;
+        self.merge_queue.clear();
+        bump(&self.counters.merges, 1);
+        self.rebuild_ordered_keys();
+
+        // An output left holding nothing (an empty store) goes with the rest.
+        let removed = self.remove_unmerged(&merged_ids);
+        self.recount_bytes()?;
+        removed
+    }
+
+    // Deletes every store file that neither the merge output nor the active
+    // file accounts for.
+    fn remove_unmerged(&mut self, merged_ids: &HashSet<u64>) -> Result<(), BitcaskError> {
+        for filepath in self.layout.entries(&self.data_path)? {
+            let id = match store_file_id(&filepath) {
+                Some(id) => id,
+                None => {
+                    continue;
+                }
+            };
+            if merged_ids.contains(&id) || id == self.active_file_id {
+                continue;
+            }
+            self.read_handles.get_mut().unwrap().remove(&id);
+            fs::remove_file(filepath)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every key by removing all data and hint files, then carries on
+    /// writing to a fresh active file. The handle and its lock stay valid.
+    pub fn clear(&mut self) -> Result<(), BitcaskError> {
+        self.check_writable()?;
+        // The active file goes too, with nothing to hint.
+        self.active_file = None;
+        if let Err(e) = self.remove_store_files() {
+            // Whatever is left on disk is what the store still holds, and
+            // writes go on into a fresh active file.
+            self.rotate()?;
+            self.reindex()?;
+            return Err(e);
+        }
+        self.read_handles.get_mut().unwrap().clear();
+        self.key_dir.clear();
+        if let Some(hashed) = &mut self.hashed {
+            hashed.clear();
+        }
+        self.merge_queue.clear();
+        self.rebuild_ordered_keys();
+        self.rotate()?;
+        for dir in self.layout.dirs(&self.data_path) {
+            sync_dir(&dir)?;
+        }
+        self.recount_bytes()
+    }
+
+    fn remove_store_files(&self) -> Result<(), BitcaskError> {
+        for filepath in self.layout.entries(&self.data_path)? {
+            if store_file_id(&filepath).is_some() {
+                fs::remove_file(filepath)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Threads `init` through `f` for every live key/value pair.
+    pub fn fold<T, F: FnMut(T, &[u8], &[u8]) -> T>(
+        &self,
+        init: T,
+        mut f: F,
+    ) -> Result<T, BitcaskError> {
+        let mut acc = init;
+        let now = self.now();
+        for (key, kd) in &self.key_dir {
+            if !kd.is_live(now) {
+                continue;
+            }
+            let value = self.read_value(key, kd)?;
+            acc = f(acc, key, &value);
+        }
+        Ok(acc)
+    }
+
+    /// Hands any bytes still buffered in the process to the OS. They survive
+    /// a crash of this process, but not of the machine; see `sync`.
+    pub fn flush(&mut self) -> Result<(), BitcaskError> {
+        if let Some(active_file) = &mut self.active_file {
+            active_file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes, then fsyncs the active file so everything written so far is
+    /// on disk.
+    pub fn sync(&mut self) -> Result<(), BitcaskError> {
+        self.flush()?;
+        if let Some(active_file) = &self.active_file {
+            active_file.get_ref().sync_all()?;
+        }
+        self.unsynced_writes = 0;
+        Ok(())
+    }
+
+    // Passes what was just written on to the OS for the flusher to sync, and
+    // reports a sync of its that failed.
+    fn hand_to_flusher(&mut self) -> Result<(), BitcaskError> {
+        self.flush()?;
+        match self.flusher.as_ref().and_then(Flusher::take_error) {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the writer that last had this directory open closed it
+    /// cleanly. If it crashed instead, its last writes may be cut short;
+    /// `verify` checks every record.
+    pub fn was_clean_shutdown(&self) -> bool {
+        self.clean_shutdown
+    }
+
+    // Records that everything this writer wrote made it to disk.
+    fn mark_clean(&self) -> io::Result<()> {
+        fs::File::create(self.data_path.join(CLEAN_FILE))?;
+        sync_dir(&self.data_path)
+    }
+
+    /// Syncs everything written, marks the directory as cleanly closed and
+    /// releases its lock. Dropping the handle does the same, but has to
+    /// swallow any error.
+    pub fn close(mut self) -> Result<(), BitcaskError> {
+        self.sync()?;
+        self.flusher = None;
+        self.active_file = None;
+        if let Some(lock_file) = self.lock_file.take() {
+            self.mark_clean()?;
+            lock_file.unlock()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Bitcask {
+    fn drop(&mut self) {
+        if let Some(lock_file) = self.lock_file.take() {
+            if self.sync().is_ok() {
+                let _ = self.mark_clean();
+            }
+            let _ = lock_file.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{clock::MockClock, test_util::fresh_dir};
+
+    fn now() -> u64 {
+        SystemClock.now()
+    }
+
+    #[test]
+    fn test_get_put() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test1")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let result = bitcask.get(b"key1").expect("Failed to get value");
+        assert_eq!(result, b"value1".to_vec());
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test2")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let keys = bitcask.list_keys();
+        assert_eq!(keys, Some(vec![&b"key1".to_vec()]));
+    }
+
+    #[test]
+    fn test_build_keydir() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test3")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+
+        let key_dir = build_keydir(
+            "/tmp/test3",
+            Layout::default(),
+            RecoveryMode::Strict,
+            None,
+            now(),
+        )
+        .expect("Failed to build keydir")
+        .0;
+        assert_eq!(key_dir.len(), 1);
+        assert!(key_dir.contains_key(b"key1".as_slice()));
+    }
+
+    #[test]
+    fn test_keydir() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test5")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open("/tmp/test5").expect("Failed to open Bitcask");
+        let result = bitcask.get(b"key1").expect("Failed to get value");
+        assert_eq!(result, b"value1".to_vec());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test4")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask2 = Bitcask::open("/tmp/test4").expect("Failed to open Bitcask");
+        let _ = bitcask2.merge();
+        bitcask2.close().expect("Failed to close Bitcask");
+
+        let bitcask3 = Bitcask::open("/tmp/test4").expect("Failed to open Bitcask");
+        let val1 = bitcask3.get(b"key1").expect("Failed to get value");
+        let val2 = bitcask3.get(b"key2").expect("Failed to get value");
+
+        let mut files = HashSet::new();
+
+        bitcask3.key_dir.iter().for_each(|(_, v)| {
+            files.insert(v.file_id);
+        });
 
         assert_eq!(files.len(), 1);
         assert_eq!(val1, b"value1".to_vec());
         assert_eq!(val2, b"value2".to_vec());
     }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF43926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF43926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn test_data_entry_crc() {
+        let entry =
+            DataFileEntry::with_timestamp(b"key1".to_vec(), b"value1".to_vec(), now(), 0, 0);
+        let bytes = entry.to_bytes();
+
+        let stored = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let expected = crc32(&[&bytes[8..]]) as u64;
+        assert_ne!(stored, 0);
+        assert_eq!(stored, expected);
+
+        let same = DataFileEntry {
+            crc: DataFileEntry::checksum(entry.timestamp, 0, 0, 4, 6, b"key1", b"value1"),
+            timestamp: entry.timestamp,
+            expiry: 0,
+            flags: 0,
+            key_size: 4,
+            value_size: 6,
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+        };
+        assert_eq!(same.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_get_corrupt_record() {
+        let path = fresh_dir("/tmp/test6");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.sync().expect("Failed to sync");
+
+        let kd = bitcask.key_dir.get(b"key1".as_slice()).unwrap();
+        let (file_id, value_pos) = (kd.file_id, kd.value_pos);
+        let filepath = Path::new(path).join(format!("{}.dat", file_id));
+        let mut bytes = fs::read(&filepath).unwrap();
+        bytes[value_pos as usize] ^= 0xFF;
+        fs::write(&filepath, bytes).unwrap();
+
+        match bitcask.get(b"key1") {
+            Err(BitcaskError::CorruptRecord {
+                file_id: id,
+                value_pos: pos,
+            }) => {
+                assert_eq!(id, file_id);
+                assert_eq!(pos, value_pos);
+            }
+            other => panic!("Expected CorruptRecord, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rotate_active_file() {
+        let path = fresh_dir("/tmp/test7");
+        let options = Options {
+            max_file_size: 100,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..10 {
+            bitcask
+                .put(
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+                .expect("Failed to put value");
+        }
+
+        let dat_files = fs::read_dir(path)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "dat")
+            .count();
+        assert!(dat_files > 1);
+
+        for i in 0..10 {
+            let value = bitcask
+                .get(format!("key{}", i).as_bytes())
+                .expect("Failed to get value");
+            assert_eq!(value, format!("value{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let path = fresh_dir("/tmp/test8");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key2").expect("Failed to delete key");
+
+        assert!(bitcask.contains_key(b"key1"));
+        assert!(!bitcask.contains_key(b"key2"));
+        assert!(!bitcask.contains_key(b"key3"));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.contains_key(b"key1"));
+        assert!(!bitcask.contains_key(b"key2"));
+        assert!(!bitcask.contains_key(b"key3"));
+    }
+
+    #[test]
+    fn test_put_returns_previous_value() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test9")).expect("Failed to open Bitcask");
+        let old = bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(old, None);
+
+        let old = bitcask
+            .put(b"key1".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(old, Some(b"value1".to_vec()));
+
+        bitcask.delete(b"key1").expect("Failed to delete key");
+        let old = bitcask
+            .put(b"key1".to_vec(), b"value3".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(old, None);
+    }
+
+    #[test]
+    fn test_put_write_error() {
+        let path = fresh_dir("/tmp/test10");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+
+        // Permission bits don't stop root, so swap the active file for a
+        // handle that was opened read-only to make the write fail.
+        let filepath = Path::new(path).join(format!("{}.dat", bitcask.active_file_id));
+        bitcask.active_file = Some(BufWriter::with_capacity(
+            0,
+            Arc::new(fs::File::open(filepath).unwrap()),
+        ));
+        let writer_pos = bitcask.writer_pos;
+
+        let result = bitcask.put(b"key2".to_vec(), b"value2".to_vec());
+        assert!(matches!(result, Err(BitcaskError::Io(_))));
+        assert_eq!(bitcask.writer_pos, writer_pos);
+        assert!(!bitcask.contains_key(b"key2"));
+        assert_eq!(
+            bitcask.get(b"key1").expect("Failed to get value"),
+            b"value1"
+        );
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test11")).expect("Failed to open Bitcask");
+        for (key, value) in [(b"a", 1u64), (b"b", 2), (b"c", 3), (b"d", 100)] {
+            bitcask
+                .put(key.to_vec(), value.to_le_bytes().to_vec())
+                .expect("Failed to put value");
+        }
+        bitcask.delete(b"d").expect("Failed to delete key");
+
+        let total = bitcask
+            .fold(0, |acc, _, value| {
+                acc + u64::from_le_bytes(value.try_into().unwrap())
+            })
+            .expect("Failed to fold");
+        assert_eq!(total, 6);
+    }
+
+    #[test]
+    fn test_lock_dir() {
+        let path = fresh_dir("/tmp/test12");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(matches!(
+            Bitcask::open(path),
+            Err(BitcaskError::AlreadyLocked)
+        ));
+
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        drop(bitcask);
+        Bitcask::open(path).expect("Failed to open Bitcask");
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let options = Options {
+            sync_policy: SyncPolicy::EveryN(3),
+            ..Options::default()
+        };
+        let mut bitcask = Bitcask::open_with_options(fresh_dir("/tmp/test13"), options)
+            .expect("Failed to open Bitcask");
+        for i in 1..=7u64 {
+            bitcask
+                .put(b"key1".to_vec(), i.to_le_bytes().to_vec())
+                .expect("Failed to put value");
+            assert_eq!(bitcask.unsynced_writes, i % 3);
+        }
+
+        let options = Options {
+            sync_policy: SyncPolicy::EveryWrite,
+            ..Options::default()
+        };
+        let mut bitcask = Bitcask::open_with_options(fresh_dir("/tmp/test14"), options)
+            .expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.unsynced_writes, 0);
+
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test15")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.unsynced_writes, 2);
+        bitcask.sync().expect("Failed to sync");
+        assert_eq!(bitcask.unsynced_writes, 0);
+    }
+
+    #[test]
+    fn test_read_exact_at() {
+        let path = fresh_dir("/tmp/test16");
+        fs::create_dir_all(path).unwrap();
+        let filepath = Path::new(path).join("file");
+        fs::write(&filepath, b"0123456789").unwrap();
+        let file = fs::File::open(&filepath).unwrap();
+
+        let mut buf = [0u8; 4];
+        read_exact_at(&file, &mut buf, 3).unwrap();
+        assert_eq!(&buf, b"3456");
+        read_exact_at(&file, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"0123");
+
+        let err = read_exact_at(&file, &mut buf, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut bitcask = Bitcask::open(fresh_dir("/tmp/test17")).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key3".to_vec(), b"value3".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key3").expect("Failed to delete key");
+
+        let mut pairs = bitcask
+            .iter()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to iterate");
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_hint() {
+        let path = fresh_dir("/tmp/test18");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key1".to_vec(), b"value3".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key2").expect("Failed to delete key");
+        let sealed_id = bitcask.active_file_id;
+        bitcask.rotate().expect("Failed to rotate");
+        bitcask
+            .put(b"key3".to_vec(), b"value4".to_vec())
+            .expect("Failed to put value");
+
+        assert!(matches!(
+            bitcask.generate_hint(bitcask.active_file_id),
+            Err(BitcaskError::ActiveFile)
+        ));
+
+        let without_hint = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+            .expect("Failed to build keydir")
+            .0;
+        bitcask
+            .generate_hint(sealed_id)
+            .expect("Failed to generate hint");
+        assert!(Path::new(path).join(format!("{}.hint", sealed_id)).exists());
+        let with_hint = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+            .expect("Failed to build keydir")
+            .0;
+        assert_eq!(with_hint, without_hint);
+        assert!(!with_hint.contains_key(b"key2".as_slice()));
+    }
+
+    #[test]
+    fn test_build_keydir_tombstones() {
+        let path = fresh_dir("/tmp/test19");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key1").expect("Failed to delete key");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(!bitcask.key_dir.contains_key(b"key1".as_slice()));
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert_eq!(
+            bitcask.get(b"key2").expect("Failed to get value"),
+            b"value2"
+        );
+    }
+
+    #[test]
+    fn test_auto_merge() {
+        let path = fresh_dir("/tmp/test20");
+        let options = Options {
+            max_file_size: 50,
+            auto_merge_threshold: 0.5,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        let count_files = || {
+            fs::read_dir(path)
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "dat")
+                .count()
+        };
+
+        for key in [b"key1", b"key2", b"key3"] {
+            bitcask
+                .put(key.to_vec(), b"value0".to_vec())
+                .expect("Failed to put value");
+        }
+        assert_eq!(bitcask.dead_ratio(), 0.0);
+        for i in 1..=3 {
+            bitcask
+                .put(b"key1".to_vec(), format!("value{}", i).into_bytes())
+                .expect("Failed to put value");
+        }
+        assert_eq!(bitcask.dead_ratio(), 0.5);
+        let files_before = count_files();
+
+        bitcask
+            .put(b"key1".to_vec(), b"value4".to_vec())
+            .expect("Failed to put value");
+        assert!(count_files() < files_before);
+        assert!(bitcask.dead_ratio() < 0.5);
+        assert_eq!(
+            bitcask.get(b"key1").expect("Failed to get value"),
+            b"value4"
+        );
+        assert_eq!(
+            bitcask.get(b"key2").expect("Failed to get value"),
+            b"value0"
+        );
+        assert_eq!(
+            bitcask.get(b"key3").expect("Failed to get value"),
+            b"value0"
+        );
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let path = fresh_dir("/tmp/test21");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put_with_ttl(
+                b"key1".to_vec(),
+                b"value1".to_vec(),
+                Duration::from_millis(200),
+            )
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(
+            bitcask.get(b"key1").expect("Failed to get value"),
+            b"value1"
+        );
+        assert!(bitcask.contains_key(b"key1"));
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert!(!bitcask.contains_key(b"key1"));
+        assert_eq!(bitcask.iter().count(), 1);
+
+        bitcask.merge().expect("Failed to merge");
+        assert!(!bitcask.key_dir.contains_key(b"key1".as_slice()));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(!bitcask.contains_key(b"key1"));
+        assert_eq!(
+            bitcask.get(b"key2").expect("Failed to get value"),
+            b"value2"
+        );
+    }
+
+    #[test]
+    fn test_open_uncreatable_dir() {
+        let path = fresh_dir("/tmp/test22");
+        fs::create_dir_all(path).unwrap();
+        let filepath = Path::new(path).join("file");
+        fs::write(&filepath, b"not a directory").unwrap();
+
+        let result = Bitcask::open(filepath.join("db"));
+        assert!(matches!(result, Err(BitcaskError::Io(_))));
+    }
+
+    #[test]
+    fn test_put_batch() {
+        let path = fresh_dir("/tmp/test23");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let entries = (0..1000)
+            .map(|i| {
+                (
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+        bitcask.put_batch(entries).expect("Failed to put batch");
+        assert_eq!(bitcask.unsynced_writes, 0);
+
+        for i in 0..1000 {
+            let value = bitcask
+                .get(format!("key{}", i).as_bytes())
+                .expect("Failed to get value");
+            assert_eq!(value, format!("value{}", i).into_bytes());
+        }
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.key_dir.len(), 1000);
+        assert_eq!(
+            bitcask.get(b"key999").expect("Failed to get value"),
+            b"value999"
+        );
+    }
+
+    #[test]
+    fn test_len() {
+        let path = fresh_dir("/tmp/test24");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.is_empty());
+
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.len(), 2);
+
+        bitcask
+            .put(b"key1".to_vec(), b"value3".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.len(), 2);
+
+        bitcask.delete(b"key1").expect("Failed to delete key");
+        assert_eq!(bitcask.len(), 1);
+        bitcask.delete(b"key2").expect("Failed to delete key");
+        assert_eq!(bitcask.len(), 0);
+        assert!(bitcask.is_empty());
+    }
+
+    #[test]
+    fn test_delete_returns_existed() {
+        let path = fresh_dir("/tmp/test25");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+
+        assert!(bitcask.delete(b"key1").expect("Failed to delete key"));
+        let writer_pos = bitcask.writer_pos;
+        assert!(!bitcask.delete(b"key1").expect("Failed to delete key"));
+        assert!(!bitcask.delete(b"key2").expect("Failed to delete key"));
+        assert_eq!(bitcask.writer_pos, writer_pos);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let path = fresh_dir("/tmp/test26");
+        assert!(Bitcask::open_read_only(path).is_err());
+
+        let mut writer = Bitcask::open(path).expect("Failed to open Bitcask");
+        writer
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let files = fs::read_dir(path).unwrap().count();
+
+        let mut reader = Bitcask::open_read_only(path).expect("Failed to open Bitcask");
+        assert_eq!(fs::read_dir(path).unwrap().count(), files);
+        assert_eq!(reader.get(b"key1").expect("Failed to get value"), b"value1");
+        assert!(matches!(
+            reader.put(b"key2".to_vec(), b"value2".to_vec()),
+            Err(BitcaskError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.delete(b"key1"),
+            Err(BitcaskError::ReadOnly)
+        ));
+        assert!(matches!(reader.merge(), Err(BitcaskError::ReadOnly)));
+
+        writer
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        assert!(!reader.contains_key(b"key2"));
+        reader.reload().expect("Failed to reload");
+        assert_eq!(reader.get(b"key2").expect("Failed to get value"), b"value2");
+    }
+
+    #[test]
+    fn test_compression() {
+        let path = fresh_dir("/tmp/test27");
+        let value = br#"{"name": "kuro", "kind": "bitcask"}"#.repeat(100);
+        for compression in [Compression::Lz4, Compression::Snappy] {
+            let options = Options {
+                compression,
+                ..Options::default()
+            };
+            let mut bitcask =
+                Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+            let key = format!("{:?}", compression).into_bytes();
+            bitcask
+                .put(key.clone(), value.clone())
+                .expect("Failed to put value");
+            assert_eq!(bitcask.get(&key).expect("Failed to get value"), value);
+            let on_disk =
+                fs::metadata(Path::new(path).join(format!("{}.dat", bitcask.active_file_id)))
+                    .unwrap()
+                    .len();
+            assert!(on_disk < value.len() as u64);
+        }
+
+        // Records stay readable after the option changes, and a merge
+        // rewrites them with whatever is configured now.
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"Lz4").expect("Failed to get value"), value);
+        assert_eq!(bitcask.get(b"Snappy").expect("Failed to get value"), value);
+        bitcask.merge().expect("Failed to merge");
+        assert_eq!(bitcask.get(b"Lz4").expect("Failed to get value"), value);
+        assert!(bitcask.total_bytes > 2 * value.len() as u64);
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = fresh_dir("/tmp/test28");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let stats = bitcask.stats().expect("Failed to get stats");
+        assert_eq!(stats.data_files, 1);
+        assert_eq!(stats.total_bytes, FILE_HEADER_SIZE);
+        assert_eq!(stats.reclaimable_bytes, 0);
+
+        for i in 0..5u8 {
+            bitcask
+                .put(vec![i], vec![i; 10])
+                .expect("Failed to put value");
+        }
+        bitcask
+            .put(vec![0], vec![1; 10])
+            .expect("Failed to put value");
+        bitcask.delete(&[1]).expect("Failed to delete key");
+        let stats = bitcask.stats().expect("Failed to get stats");
+        assert_eq!(stats.live_keys, 4);
+        assert_eq!(stats.tombstones, 1);
+        assert!(stats.reclaimable_bytes > 0);
+        assert_eq!(
+            stats.total_bytes,
+            fs::metadata(Path::new(path).join(format!("{}.dat", bitcask.active_file_id)))
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn test_put_after_merge() {
+        let path = fresh_dir("/tmp/test29");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask.merge().expect("Failed to merge");
+        let merged_ids: HashSet<u64> = bitcask.key_dir.values().map(|kd| kd.file_id).collect();
+        assert!(!merged_ids.contains(&bitcask.active_file_id));
+        assert_eq!(bitcask.writer_pos, FILE_HEADER_SIZE);
+
+        bitcask
+            .put(b"key2".to_vec(), b"value2b".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key3".to_vec(), b"value3".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1".to_vec());
+        assert_eq!(bitcask.get(b"key2").unwrap(), b"value2b".to_vec());
+        assert_eq!(bitcask.get(b"key3").unwrap(), b"value3".to_vec());
+    }
+
+    #[test]
+    fn test_merge_keeps_newest_value() {
+        let path = fresh_dir("/tmp/test30");
+        let options = Options {
+            max_file_size: 60,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"old".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        // The overwrite lands in the active file, the original in a sealed one.
+        bitcask
+            .put(b"key1".to_vec(), b"new".to_vec())
+            .expect("Failed to put value");
+        assert_ne!(
+            bitcask.key_dir[&b"key1".to_vec()].file_id,
+            bitcask.key_dir[&b"key2".to_vec()].file_id
+        );
+
+        bitcask.merge().expect("Failed to merge");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"new".to_vec());
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"new".to_vec());
+        assert_eq!(bitcask.get(b"key2").unwrap(), b"value2".to_vec());
+    }
+
+    #[test]
+    fn test_import() {
+        let path = fresh_dir("/tmp/test31");
+        let entries = (0..10_000u32).map(|i| {
+            (
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        });
+        let bitcask = Bitcask::import(path, entries).expect("Failed to import");
+        assert_eq!(bitcask.len(), 10_000);
+        for i in [0, 17, 4242, 9999] {
+            assert_eq!(
+                bitcask.get(format!("key{}", i).as_bytes()).unwrap(),
+                format!("value{}", i).into_bytes()
+            );
+        }
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 10_000);
+        assert_eq!(bitcask.get(b"key1234").unwrap(), b"value1234".to_vec());
+    }
+
+    #[test]
+    fn test_get_many() {
+        let path = fresh_dir("/tmp/test32");
+        let options = Options {
+            max_file_size: 100,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..6u8 {
+            bitcask
+                .put(vec![b'k', i], vec![b'v', i])
+                .expect("Failed to put value");
+        }
+        bitcask.delete(&[b'k', 3]).expect("Failed to delete key");
+        let files: HashSet<u64> = bitcask.key_dir.values().map(|kd| kd.file_id).collect();
+        assert!(files.len() > 1);
+
+        let keys: [&[u8]; 5] = [b"k\x05", b"missing", b"k\x00", b"k\x03", b"k\x02"];
+        let results = bitcask.get_many(&keys);
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap(), b"v\x05");
+        assert!(matches!(results[1], Err(BitcaskError::KeyNotFound)));
+        assert_eq!(results[2].as_ref().unwrap(), b"v\x00");
+        assert_eq!(results[4].as_ref().unwrap(), b"v\x02");
+        for (key, result) in keys.iter().zip(&results) {
+            assert_eq!(
+                result.as_ref().ok(),
+                bitcask.get(key).as_ref().ok(),
+                "mismatch for {:?}",
+                key
+            );
+        }
+
+        // Each key of a file that can't be opened gets the error itself.
+        let file_id = bitcask.key_dir[b"k\x00".as_slice()].file_id;
+        assert_ne!(file_id, bitcask.active_file_id);
+        bitcask.read_handles.lock().unwrap().clear();
+        fs::remove_file(Path::new(path).join(format!("{}.dat", file_id))).unwrap();
+        let lost: Vec<&[u8]> = keys
+            .iter()
+            .copied()
+            .filter(|key| {
+                bitcask
+                    .key_dir
+                    .get(*key)
+                    .is_some_and(|kd| kd.file_id == file_id)
+            })
+            .collect();
+        for result in bitcask.get_many(&lost) {
+            match result {
+                Err(BitcaskError::Io(e)) => assert_eq!(e.raw_os_error(), Some(2)),
+                other => panic!("expected an I/O error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_handle_cache() {
+        let path = fresh_dir("/tmp/test33");
+        let options = Options {
+            max_file_size: 100,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..6u8 {
+            bitcask
+                .put(vec![b'k', i], vec![b'v', i])
+                .expect("Failed to put value");
+        }
+        for _ in 0..1000 {
+            assert_eq!(bitcask.get(b"k\x01").unwrap(), b"v\x01");
+        }
+        let file_id = bitcask.key_dir[&b"k\x01".to_vec()].file_id;
+        let handle = Arc::clone(&bitcask.read_handles.lock().unwrap()[&file_id]);
+        bitcask.get(b"k\x01").unwrap();
+        assert!(Arc::ptr_eq(
+            &handle,
+            &bitcask.read_handles.lock().unwrap()[&file_id]
+        ));
+
+        // Merge deletes the file, so its handle must go too.
+        bitcask.merge().expect("Failed to merge");
+        assert!(!bitcask.read_handles.lock().unwrap().contains_key(&file_id));
+        assert_eq!(bitcask.get(b"k\x01").unwrap(), b"v\x01");
+        assert_eq!(bitcask.get(b"k\x05").unwrap(), b"v\x05");
+    }
+
+    #[test]
+    fn test_truncated_tail() {
+        let path = fresh_dir("/tmp/test34");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        let filepath = Path::new(path).join(format!("{}.dat", bitcask.active_file_id));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let full_len = fs::metadata(&filepath).unwrap().len();
+        let record_len = (full_len - FILE_HEADER_SIZE) / 2;
+        let file = fs::OpenOptions::new().write(true).open(&filepath).unwrap();
+        // Cut into the second record's value, then into its header.
+        for len in [full_len - 3, FILE_HEADER_SIZE + record_len + 10] {
+            file.set_len(len).unwrap();
+            let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+            assert_eq!(bitcask.len(), 1);
+            assert_eq!(bitcask.get(b"key1").unwrap(), b"value1".to_vec());
+            assert!(matches!(
+                bitcask.get(b"key2"),
+                Err(BitcaskError::KeyNotFound)
+            ));
+        }
+
+        // An empty data file is fine too.
+        file.set_len(0).unwrap();
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.is_empty());
+    }
+
+    #[test]
+    fn test_rapid_writes_and_file_ids() {
+        let path = fresh_dir("/tmp/test35");
+        fs::create_dir_all(path).unwrap();
+        let ids: Vec<u64> = (0..100)
+            .map(|_| gen_file_id(Layout::default(), path, now()).unwrap())
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"first".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key1".to_vec(), b"second".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+        // Reopening within the same millisecond still yields a newer file.
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"third".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"third".to_vec());
+    }
+
+    #[test]
+    fn test_prefix_scan() {
+        let path = fresh_dir("/tmp/test36");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for key in [
+            "user:123:name",
+            "user:123:email",
+            "user:123:age",
+            "user:1234:name",
+            "user:456:name",
+            "post:123:title",
+        ] {
+            bitcask
+                .put(key.as_bytes().to_vec(), b"value".to_vec())
+                .expect("Failed to put value");
+        }
+        bitcask
+            .delete(b"user:123:age")
+            .expect("Failed to delete key");
+
+        let mut keys: Vec<&[u8]> = bitcask.prefix_scan(b"user:123:").collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"user:123:email".as_slice(), b"user:123:name"]);
+        assert_eq!(bitcask.prefix_scan(b"user:").count(), 4);
+        assert_eq!(bitcask.prefix_scan(b"").count(), 5);
+        assert_eq!(bitcask.prefix_scan(b"nope").count(), 0);
+    }
+
+    #[test]
+    fn test_range() {
+        for ordered_index in [true, false] {
+            let path = fresh_dir("/tmp/test37");
+            let options = Options {
+                ordered_index,
+                ..Options::default()
+            };
+            let mut bitcask =
+                Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+            for key in ["a", "b", "c", "d", "e"] {
+                bitcask
+                    .put(key.as_bytes().to_vec(), key.to_uppercase().into_bytes())
+                    .expect("Failed to put value");
+            }
+            bitcask.delete(b"c").expect("Failed to delete key");
+            assert_eq!(
+                bitcask.ordered_keys.as_ref().map(|keys| keys.len()),
+                ordered_index.then_some(4)
+            );
+            let range = |bitcask: &Bitcask, start: &[u8], end: &[u8]| {
+                bitcask
+                    .range(start, end)
+                    .map(|item| item.unwrap())
+                    .collect::<Vec<_>>()
+            };
+
+            // The start is inclusive, the end exclusive, and deleted keys
+            // never show up.
+            assert_eq!(
+                range(&bitcask, b"b", b"e"),
+                vec![
+                    (b"b".to_vec(), b"B".to_vec()),
+                    (b"d".to_vec(), b"D".to_vec())
+                ]
+            );
+            assert_eq!(range(&bitcask, b"a", b"b").len(), 1);
+            assert_eq!(range(&bitcask, b"", b"z").len(), 4);
+            assert!(range(&bitcask, b"d", b"d").is_empty());
+            assert!(range(&bitcask, b"e", b"a").is_empty());
+
+            // The index survives a merge and a reopen.
+            bitcask.merge().expect("Failed to merge");
+            assert_eq!(range(&bitcask, b"a", b"f").len(), 4);
+            bitcask.close().expect("Failed to close Bitcask");
+            let mut bitcask =
+                Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+            let keys: Vec<Vec<u8>> = range(&bitcask, b"a", b"f")
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(
+                keys,
+                vec![b"a".to_vec(), b"b".to_vec(), b"d".to_vec(), b"e".to_vec()]
+            );
+
+            // A key only the ordered index has is passed over.
+            if let Some(keys) = &mut bitcask.ordered_keys {
+                keys.insert(b"b2".to_vec());
+            }
+            assert_eq!(range(&bitcask, b"a", b"f").len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_max_value_size() {
+        let path = fresh_dir("/tmp/test38");
+        let options = Options {
+            max_value_size: 8,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"12345678".to_vec())
+            .expect("Failed to put value");
+        assert!(matches!(
+            bitcask.put(b"key2".to_vec(), b"123456789".to_vec()),
+            Err(BitcaskError::ValueTooLarge)
+        ));
+        assert!(matches!(
+            bitcask.put_batch(vec![
+                (b"key3".to_vec(), b"ok".to_vec()),
+                (b"key4".to_vec(), vec![0; 100]),
+            ]),
+            Err(BitcaskError::ValueTooLarge)
+        ));
+        assert_eq!(bitcask.len(), 1);
+        assert_eq!(bitcask.writer_pos, FILE_HEADER_SIZE + HEADER_SIZE + 4 + 8);
+        assert!(bitcask.delete(b"key1").expect("Failed to delete key"));
+    }
+
+    #[test]
+    fn test_oversized_recorded_size() {
+        let path = fresh_dir("/tmp/test39");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let file_id = bitcask.active_file_id;
+        bitcask
+            .key_dir
+            .get_mut(b"key1".as_slice())
+            .unwrap()
+            .value_size = u64::MAX / 2;
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::CorruptRecord { .. })
+        ));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        // A hint claiming a huge key is refused rather than allocated.
+        let hint = HintFileEntry {
+            timestamp: 0,
+            expiry: 0,
+            key_size: u64::MAX / 2,
+            value_size: 6,
+            value_pos: FILE_HEADER_SIZE + HEADER_SIZE + 4,
+            key: b"key1".to_vec(),
+        };
+        let mut hint_file = HintWriter::new(file_header(HINT_MAGIC).to_vec());
+        hint_file.write(&hint).unwrap();
+        fs::write(
+            Path::new(path).join(format!("{}.hint", file_id)),
+            hint_file.finish().unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            Bitcask::open(path),
+            Err(BitcaskError::InvalidFileFormat)
+        ));
+    }
+
+    #[test]
+    fn test_recovery_mode() {
+        let path = fresh_dir("/tmp/test40");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for key in [b"key1", b"key2", b"key3"] {
+            bitcask
+                .put(key.to_vec(), b"value".to_vec())
+                .expect("Failed to put value");
+        }
+        let filepath = Path::new(path).join(format!("{}.dat", bitcask.active_file_id));
+        let record_len = (bitcask.writer_pos - FILE_HEADER_SIZE) / 3;
+        let middle = FILE_HEADER_SIZE + record_len;
+        bitcask.close().expect("Failed to close Bitcask");
+        let original = fs::read(&filepath).unwrap();
+        let lenient = Options {
+            recovery_mode: RecoveryMode::Lenient,
+            ..Options::default()
+        };
+
+        // Flip a byte of the middle value, then garble its key size.
+        for offset in [middle + record_len - 1, middle + FLAGS_OFFSET as u64 + 3] {
+            let mut data = original.clone();
+            data[offset as usize] ^= 0xFF;
+            fs::write(&filepath, &data).unwrap();
+
+            if offset == middle + record_len - 1 {
+                assert!(matches!(
+                    Bitcask::open(path),
+                    Err(BitcaskError::CorruptRecord { .. })
+                ));
+            }
+            let bitcask =
+                Bitcask::open_with_options(path, lenient.clone()).expect("Failed to open Bitcask");
+            assert_eq!(bitcask.get(b"key1").unwrap(), b"value".to_vec());
+            assert_eq!(bitcask.get(b"key3").unwrap(), b"value".to_vec());
+            assert!(matches!(
+                bitcask.get(b"key2"),
+                Err(BitcaskError::KeyNotFound)
+            ));
+            assert_eq!(bitcask.stats().unwrap().skipped_records, 1);
+        }
+
+        fs::write(&filepath, &original).unwrap();
+        let bitcask = Bitcask::open_with_options(path, lenient).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.stats().unwrap().skipped_records, 0);
+    }
+
+    #[test]
+    fn test_clear() {
+        let path = fresh_dir("/tmp/test41");
+        let options = Options {
+            max_file_size: 100,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..5u8 {
+            bitcask
+                .put(vec![b'k', i], vec![b'v', i])
+                .expect("Failed to put value");
+        }
+        bitcask.clear().expect("Failed to clear");
+        assert_eq!(bitcask.len(), 0);
+        assert!(matches!(
+            bitcask.get(b"k\x01"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert_eq!(bitcask.writer_pos, FILE_HEADER_SIZE);
+        assert_eq!(bitcask.stats().unwrap().data_files, 1);
+        assert!(matches!(
+            Bitcask::open(path),
+            Err(BitcaskError::AlreadyLocked)
+        ));
+
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 1);
+        assert!(matches!(
+            bitcask.get(b"k\x00"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_interrupted_merge() {
+        let path = fresh_dir("/tmp/test42");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        // What a merge that died before its rename leaves behind: complete
+        // looking output with a newer id and a different value.
+        let entry = DataFileEntry::with_timestamp(b"key1".to_vec(), b"stale".to_vec(), now(), 0, 0);
+        let leftover = Path::new(path).join(format!("{}.dat.merging", u64::MAX - 1));
+        fs::write(&leftover, entry.to_bytes()).unwrap();
+        let leftover_hint = Path::new(path).join(format!("{}.hint.merging", u64::MAX - 1));
+        fs::write(&leftover_hint, b"garbage").unwrap();
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1".to_vec());
+        assert!(!leftover.exists());
+        assert!(!leftover_hint.exists());
+
+        bitcask.merge().expect("Failed to merge");
+        let leftovers = fs::read_dir(path)
+            .unwrap()
+            .filter(|entry| {
+                let filepath = entry.as_ref().unwrap().path();
+                filepath.extension().unwrap() == MERGING_EXT
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1".to_vec());
+    }
+
+    #[test]
+    fn test_sync_dir() {
+        let path = fresh_dir("/tmp/test43");
+        assert!(sync_dir(Path::new(path)).is_err() || cfg!(not(unix)));
+        let options = Options {
+            max_file_size: 60,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        sync_dir(Path::new(path)).expect("Failed to sync directory");
+        for i in 0..3u8 {
+            bitcask
+                .put(vec![i], b"value".to_vec())
+                .expect("Failed to put value");
+        }
+        assert!(bitcask.stats().unwrap().data_files > 1);
+        bitcask.merge().expect("Failed to merge");
+    }
+
+    #[test]
+    fn test_reopen_writer_pos() {
+        let path = fresh_dir("/tmp/test44");
+        for round in 0..3u8 {
+            let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+            assert_eq!(
+                bitcask.writer_pos,
+                fs::metadata(Path::new(path).join(format!("{}.dat", bitcask.active_file_id)))
+                    .unwrap()
+                    .len()
+            );
+            bitcask
+                .put(vec![round], vec![round; 10])
+                .expect("Failed to put value");
+            bitcask
+                .put(b"shared".to_vec(), vec![round])
+                .expect("Failed to put value");
+            bitcask.close().expect("Failed to close Bitcask");
+        }
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for round in 0..3u8 {
+            assert_eq!(bitcask.get(&[round]).unwrap(), vec![round; 10]);
+        }
+        assert_eq!(bitcask.get(b"shared").unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_expected_keys() {
+        let path = fresh_dir("/tmp/test45");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for i in 0..100u32 {
+            bitcask
+                .put(i.to_le_bytes().to_vec(), i.to_be_bytes().to_vec())
+                .expect("Failed to put value");
+        }
+        let file_id = bitcask.active_file_id;
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let options = Options {
+            expected_keys: Some(10_000),
+            ..Options::default()
+        };
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert!(bitcask.key_dir.capacity() >= 10_000);
+        assert_eq!(bitcask.len(), 100);
+        assert_eq!(
+            bitcask.get(&42u32.to_le_bytes()).unwrap(),
+            42u32.to_be_bytes().to_vec()
+        );
+
+        // With a hint file the key dir is sized from its length instead.
+        bitcask
+            .generate_hint(file_id)
+            .expect("Failed to generate hint");
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 100);
+    }
+
+    #[test]
+    fn test_merge_into() {
+        let path = fresh_dir("/tmp/test46");
+        let dest = fresh_dir("/tmp/test46-copy");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3"), ("a", "4")] {
+            bitcask
+                .put(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+                .expect("Failed to put value");
+        }
+        bitcask.delete(b"b").expect("Failed to delete key");
+        let source_files = bitcask.stats().unwrap();
+
+        bitcask.merge_into(dest).expect("Failed to merge into");
+        assert_eq!(bitcask.stats().unwrap(), source_files);
+        assert_eq!(bitcask.get(b"a").unwrap(), b"4".to_vec());
+
+        let files: Vec<String> = fs::read_dir(dest)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != LOCK_FILE)
+            .collect();
+        assert_eq!(files.len(), 2);
+
+        let copy = Bitcask::open(dest).expect("Failed to open Bitcask");
+        assert_eq!(copy.len(), 2);
+        assert_eq!(copy.get(b"a").unwrap(), b"4".to_vec());
+        assert_eq!(copy.get(b"c").unwrap(), b"3".to_vec());
+        assert!(matches!(copy.get(b"b"), Err(BitcaskError::KeyNotFound)));
+    }
+
+    #[test]
+    fn test_scan_many_records() {
+        let path = fresh_dir("/tmp/test47");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for i in 0..5000u32 {
+            bitcask
+                .put(
+                    format!("key{}", i % 3000).into_bytes(),
+                    i.to_le_bytes().to_vec(),
+                )
+                .expect("Failed to put value");
+        }
+        let expected = bitcask.key_dir.clone();
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let key_dir = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+            .expect("Failed to build keydir")
+            .0;
+        assert_eq!(key_dir.len(), 3000);
+        assert_eq!(key_dir, expected);
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(
+            bitcask.get(b"key10").unwrap(),
+            3010u32.to_le_bytes().to_vec()
+        );
+        assert_eq!(
+            bitcask.get(b"key2999").unwrap(),
+            2999u32.to_le_bytes().to_vec()
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = fresh_dir("/tmp/test48");
+        let dest = fresh_dir("/tmp/test48-snapshot");
+        let options = Options {
+            max_file_size: 100,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..5u8 {
+            bitcask
+                .put(vec![b'k', i], vec![b'v', i])
+                .expect("Failed to put value");
+        }
+        bitcask.delete(b"k\x00").expect("Failed to delete key");
+        bitcask.snapshot(dest).expect("Failed to snapshot");
+
+        bitcask
+            .put(b"k\x01".to_vec(), b"later".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"new".to_vec(), b"later".to_vec())
+            .expect("Failed to put value");
+
+        let copy = Bitcask::open(dest).expect("Failed to open Bitcask");
+        let mut keys: Vec<&[u8]> = copy.prefix_scan(b"").collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![b"k\x01".as_slice(), b"k\x02", b"k\x03", b"k\x04"]
+        );
+        assert_eq!(copy.get(b"k\x01").unwrap(), b"v\x01".to_vec());
+        assert_eq!(bitcask.get(b"k\x01").unwrap(), b"later".to_vec());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sync_error() {
+        let path = fresh_dir("/tmp/test49");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.flush().expect("Failed to flush");
+        bitcask.sync().expect("Failed to sync");
+
+        // Character devices refuse fsync.
+        bitcask.active_file = Some(BufWriter::new(Arc::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/null")
+                .unwrap(),
+        )));
+        bitcask.flush().expect("Failed to flush");
+        assert!(matches!(bitcask.sync(), Err(BitcaskError::Io(_))));
+    }
+
+    #[test]
+    fn test_file_format_version() {
+        let path = fresh_dir("/tmp/test50");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let file_id = bitcask.active_file_id;
+        bitcask.close().expect("Failed to close Bitcask");
+        let filepath = Path::new(path).join(format!("{}.dat", file_id));
+        let original = fs::read(&filepath).unwrap();
+        assert_eq!(&original[..4], DATA_MAGIC);
+
+        let mut data = original.clone();
+        data[4..8].copy_from_slice(&99u32.to_le_bytes());
+        fs::write(&filepath, &data).unwrap();
+        assert!(matches!(
+            Bitcask::open(path),
+            Err(BitcaskError::UnsupportedVersion(99))
+        ));
+
+        let mut data = original.clone();
+        data[..4].copy_from_slice(b"NOPE");
+        fs::write(&filepath, &data).unwrap();
+        assert!(matches!(
+            Bitcask::open(path),
+            Err(BitcaskError::InvalidFileFormat)
+        ));
+
+        // Hint files carry the same header.
+        fs::write(&filepath, &original).unwrap();
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .generate_hint(file_id)
+            .expect("Failed to generate hint");
+        bitcask.close().expect("Failed to close Bitcask");
+        let hint_filepath = filepath.with_extension("hint");
+        let mut hint = fs::read(&hint_filepath).unwrap();
+        assert_eq!(&hint[..4], HINT_MAGIC);
+        hint[4..8].copy_from_slice(&0u32.to_le_bytes());
+        fs::write(&hint_filepath, &hint).unwrap();
+        assert!(matches!(
+            Bitcask::open(path),
+            Err(BitcaskError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn test_get_entry() {
+        let path = fresh_dir("/tmp/test51");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let before = now();
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let entry = bitcask.get_entry(b"key1").expect("Failed to get entry");
+        assert_eq!(entry.value, b"value1".to_vec());
+        assert_eq!(entry.file_id, bitcask.active_file_id);
+        assert_eq!(entry.value_pos, FILE_HEADER_SIZE + HEADER_SIZE + 4);
+        assert!(entry.timestamp >= before && entry.timestamp <= now());
+
+        bitcask.delete(b"key1").expect("Failed to delete key");
+        assert!(matches!(
+            bitcask.get_entry(b"key1"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert!(matches!(
+            bitcask.get_entry(b"missing"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_merge_keeps_timestamps() {
+        let path = fresh_dir("/tmp/test52");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        let written = bitcask.get_entry(b"key1").unwrap().timestamp;
+        std::thread::sleep(Duration::from_millis(5));
+        bitcask.merge().expect("Failed to merge");
+        assert_eq!(bitcask.get_entry(b"key1").unwrap().timestamp, written);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        // Both the hint and a plain scan of the merged file agree.
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let entry = bitcask.get_entry(b"key1").unwrap();
+        assert_eq!(entry.timestamp, written);
+        fs::remove_file(Path::new(path).join(format!("{}.hint", entry.file_id))).unwrap();
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get_entry(b"key1").unwrap().timestamp, written);
+    }
+
+    #[test]
+    fn test_modify() {
+        let path = fresh_dir("/tmp/test53");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let increment = |value: Option<Vec<u8>>| {
+            let count = value.map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+            Some((count + 1).to_le_bytes().to_vec())
+        };
+        for _ in 0..5 {
+            bitcask
+                .modify(b"counter", increment)
+                .expect("Failed to modify");
+        }
+        assert_eq!(
+            bitcask.get(b"counter").unwrap(),
+            5u64.to_le_bytes().to_vec()
+        );
+
+        bitcask
+            .modify(b"counter", |_| None)
+            .expect("Failed to modify");
+        assert!(!bitcask.contains_key(b"counter"));
+        bitcask
+            .modify(b"counter", increment)
+            .expect("Failed to modify");
+        assert_eq!(
+            bitcask.get(b"counter").unwrap(),
+            1u64.to_le_bytes().to_vec()
+        );
+    }
+
+    #[test]
+    fn test_put_large_value() {
+        let path = fresh_dir("/tmp/test54");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let value: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let key = b"big".to_vec();
+        bitcask
+            .put(key.clone(), value.clone())
+            .expect("Failed to put value");
+        assert_eq!(
+            bitcask.writer_pos,
+            FILE_HEADER_SIZE + HEADER_SIZE + key.len() as u64 + value.len() as u64
+        );
+        assert_eq!(bitcask.get(&key).unwrap(), value);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(&key).unwrap(), value);
+    }
+
+    #[test]
+    fn test_foreign_files() {
+        let path = fresh_dir("/tmp/test55");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for i in 0..3u8 {
+            bitcask
+                .put(b"key1".to_vec(), vec![i])
+                .expect("Failed to put value");
+        }
+        let foreign = ["README.txt", "notes.dat", "backup.hint", "2024.txt", "17"];
+        for name in foreign {
+            fs::write(Path::new(path).join(name), b"not ours").unwrap();
+        }
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 1);
+        bitcask.merge().expect("Failed to merge");
+        assert_eq!(bitcask.dead_ratio(), 0.0);
+        assert_eq!(bitcask.get(b"key1").unwrap(), vec![2]);
+        for name in foreign {
+            assert_eq!(fs::read(Path::new(path).join(name)).unwrap(), b"not ours");
+        }
+
+        bitcask.clear().expect("Failed to clear");
+        for name in foreign {
+            assert!(Path::new(path).join(name).exists());
+        }
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        let path = fresh_dir("/tmp/test56");
+        let options = Options {
+            write_buffer_size: 64 * 1024,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        let filepath = Path::new(path).join(format!("{}.dat", bitcask.active_file_id));
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+
+        // Nothing has reached the file yet, but reads see both writes.
+        assert_eq!(fs::metadata(&filepath).unwrap().len(), FILE_HEADER_SIZE);
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1");
+        let results = bitcask.get_many(&[b"key1", b"key2"]);
+        assert_eq!(results[1].as_ref().unwrap(), b"value2");
+        assert_eq!(
+            bitcask
+                .put(b"key1".to_vec(), b"value3".to_vec())
+                .expect("Failed to put value"),
+            Some(b"value1".to_vec())
+        );
+
+        bitcask.flush().expect("Failed to flush");
+        assert_eq!(fs::metadata(&filepath).unwrap().len(), bitcask.writer_pos);
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value3");
+        bitcask
+            .put(b"key3".to_vec(), b"value4".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value3");
+        assert_eq!(bitcask.get(b"key3").unwrap(), b"value4");
+    }
+
+    #[test]
+    fn test_drop_flushes() {
+        let path = fresh_dir("/tmp/test57");
+        let options = Options {
+            write_buffer_size: 64 * 1024,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        drop(bitcask);
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_keys_page() {
+        for ordered_index in [false, true] {
+            let path = fresh_dir("/tmp/test58");
+            let options = Options {
+                ordered_index,
+                ..Options::default()
+            };
+            let mut bitcask =
+                Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+            for i in 0..25 {
+                bitcask
+                    .put(format!("key{:02}", i).into_bytes(), b"value".to_vec())
+                    .expect("Failed to put value");
+            }
+            bitcask
+                .put(b"gone".to_vec(), b"value".to_vec())
+                .expect("Failed to put value");
+            bitcask.delete(b"gone").expect("Failed to delete key");
+
+            let mut seen = Vec::new();
+            let mut sizes = Vec::new();
+            loop {
+                let page = bitcask.keys_page(seen.last().map(Vec::as_slice), 10);
+                if page.is_empty() {
+                    break;
+                }
+                sizes.push(page.len());
+                seen.extend(page);
+            }
+            assert_eq!(sizes, [10, 10, 5]);
+            let expected: Vec<Vec<u8>> = (0..25)
+                .map(|i| format!("key{:02}", i).into_bytes())
+                .collect();
+            assert_eq!(seen, expected);
+
+            // A key only the ordered index has is passed over.
+            if let Some(keys) = &mut bitcask.ordered_keys {
+                keys.insert(b"key05x".to_vec());
+            }
+            assert_eq!(
+                bitcask.keys_page(Some(b"key05"), 2),
+                [b"key06".to_vec(), b"key07".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_split_output() {
+        let path = fresh_dir("/tmp/test59");
+        let options = Options {
+            max_file_size: 1024,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for round in 0..3u8 {
+            for i in 0..50u8 {
+                bitcask
+                    .put(vec![i], vec![round; 40])
+                    .expect("Failed to put value");
+            }
+        }
+        bitcask.merge().expect("Failed to merge");
+
+        let mut dat_ids = HashSet::new();
+        let mut hint_ids = HashSet::new();
+        for entry in fs::read_dir(path).unwrap() {
+            let filepath = entry.unwrap().path();
+            let id = match store_file_id(&filepath) {
+                Some(id) if id != bitcask.active_file_id => id,
+                _ => continue,
+            };
+            if filepath.extension().unwrap() == "dat" {
+                assert!(fs::metadata(&filepath).unwrap().len() <= 1024);
+                dat_ids.insert(id);
+            } else {
+                hint_ids.insert(id);
+            }
+        }
+        assert!(dat_ids.len() > 1);
+        assert_eq!(dat_ids, hint_ids);
+        assert!(dat_ids.iter().all(|id| *id < bitcask.active_file_id));
+        for i in 0..50u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![2; 40]);
+        }
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 50);
+        for i in 0..50u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![2; 40]);
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let path = fresh_dir("/tmp/test60");
+        let options = Options {
+            encryption: Some(EncryptionConfig::new([42; 32])),
+            compression: Compression::Lz4,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        let secret = b"top secret value ".repeat(10);
+        bitcask
+            .put(b"key1".to_vec(), secret.clone())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key2").expect("Failed to delete key");
+        assert_eq!(bitcask.get(b"key1").unwrap(), secret);
+        let before = bitcask.get_entry(b"key1").unwrap();
+        bitcask.close().expect("Failed to close Bitcask");
+
+        // Nothing readable on disk.
+        let mut on_disk = Vec::new();
+        for entry in fs::read_dir(path).unwrap() {
+            let filepath = entry.unwrap().path();
+            if filepath.extension().is_some_and(|ext| ext == "dat") {
+                on_disk.extend(fs::read(filepath).unwrap());
+            }
+        }
+        assert!(!on_disk.windows(10).any(|w| w == b"top secret"));
+
+        // Merge re-encrypts under fresh nonces.
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        bitcask.merge().expect("Failed to merge");
+        let after = bitcask.get_entry(b"key1").unwrap();
+        assert_eq!(after.value, secret);
+        assert_eq!(after.timestamp, before.timestamp);
+        assert!(!bitcask.contains_key(b"key2"));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let wrong_key = Options {
+            encryption: Some(EncryptionConfig::new([7; 32])),
+            ..Options::default()
+        };
+        let bitcask = Bitcask::open_with_options(path, wrong_key).expect("Failed to open Bitcask");
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::Decryption)
+        ));
+        drop(bitcask);
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::Decryption)
+        ));
+        // What can't be read can still be replaced or deleted.
+        assert_eq!(
+            bitcask.put(b"key1".to_vec(), b"plain".to_vec()).unwrap(),
+            None
+        );
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"plain");
+        bitcask.close().expect("Failed to close Bitcask");
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key3".to_vec(), b"value3".to_vec())
+            .expect("Failed to put value");
+        drop(bitcask);
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.delete(b"key3").expect("Failed to delete key"));
+        assert!(!bitcask.contains_key(b"key3"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let path = fresh_dir("/tmp/test61");
+        let options = Options {
+            max_file_size: 200,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..6u8 {
+            bitcask
+                .put(vec![i], vec![i; 50])
+                .expect("Failed to put value");
+        }
+        let clean = bitcask.verify().expect("Failed to verify");
+        assert_eq!(clean.total_records, 6);
+        assert!(clean.corrupt_records.is_empty());
+
+        // Flip a value byte of one record in each of two different files.
+        let first = bitcask.get_entry(&[1]).unwrap();
+        let second = bitcask.get_entry(&[4]).unwrap();
+        assert_ne!(first.file_id, second.file_id);
+        let mut expected = Vec::new();
+        for entry in [&first, &second] {
+            let filepath = Path::new(path).join(format!("{}.dat", entry.file_id));
+            let mut bytes = fs::read(&filepath).unwrap();
+            bytes[entry.value_pos as usize] ^= 0xFF;
+            fs::write(&filepath, &bytes).unwrap();
+            expected.push((entry.file_id, entry.value_pos - HEADER_SIZE - 1));
+        }
+
+        let report = bitcask.verify().expect("Failed to verify");
+        assert_eq!(report.total_records, 6);
+        assert_eq!(report.corrupt_records, expected);
+        assert_eq!(bitcask.verify().expect("Failed to verify"), report);
+    }
+
+    #[test]
+    fn test_get_deleted() {
+        let path = fresh_dir("/tmp/test62");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key1").expect("Failed to delete key");
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert!(matches!(
+            bitcask.get_many(&[b"key1"])[0],
+            Err(BitcaskError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_sentinel_value() {
+        let path = fresh_dir("/tmp/test63");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), LEGACY_TOMBSTONE.to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key2").expect("Failed to delete key");
+        assert_eq!(bitcask.get(b"key1").unwrap(), LEGACY_TOMBSTONE);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), LEGACY_TOMBSTONE);
+        assert!(!bitcask.contains_key(b"key2"));
+        bitcask.merge().expect("Failed to merge");
+        assert_eq!(bitcask.get(b"key1").unwrap(), LEGACY_TOMBSTONE);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), LEGACY_TOMBSTONE);
+        assert_eq!(bitcask.len(), 1);
+    }
+
+    #[test]
+    fn test_legacy_tombstones() {
+        let path = fresh_dir("/tmp/test64");
+        fs::create_dir_all(path).unwrap();
+        // A version 1 file: the sentinel value deletes key1.
+        let mut bytes = DATA_MAGIC.to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        for (key, value) in [
+            (&b"key1"[..], &b"value1"[..]),
+            (b"key2", b"value2"),
+            (b"key1", LEGACY_TOMBSTONE),
+        ] {
+            let crc =
+                DataFileEntry::checksum(1, 0, 0, key.len() as u64, value.len() as u64, key, value);
+            bytes.extend(record_bytes(crc, 1, 0, 0, key, value));
+        }
+        fs::write(Path::new(path).join("1.dat"), bytes).unwrap();
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(!bitcask.contains_key(b"key1"));
+        assert_eq!(bitcask.get(b"key2").unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_sharded_layout() {
+        let path = fresh_dir("/tmp/test65");
+        let options = Options {
+            shards: 4,
+            max_file_size: 200,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        for round in 0..2u8 {
+            for i in 0..20u8 {
+                bitcask
+                    .put(vec![i], vec![round; 50])
+                    .expect("Failed to put value");
+            }
+        }
+        let files_in = |dir: &Path| {
+            fs::read_dir(dir)
+                .unwrap()
+                .filter(|entry| store_file_id(&entry.as_ref().unwrap().path()).is_some())
+                .count()
+        };
+        assert_eq!(files_in(Path::new(path)), 0);
+        let used_shards = (0..4)
+            .filter(|shard| files_in(&Path::new(path).join(format!("{:03}", shard))) > 0)
+            .count();
+        assert!(used_shards > 1);
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![1; 50]);
+        }
+
+        bitcask.merge().expect("Failed to merge");
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![1; 50]);
+        }
+        assert_eq!(bitcask.dead_ratio(), 0.0);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 20);
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![1; 50]);
+        }
+    }
+
+    #[test]
+    fn test_drop_deleted_keys() {
+        let path = fresh_dir("/tmp/test66");
+        let options = Options {
+            drop_deleted_keys: true,
+            ..Options::default()
+        };
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        for key in [b"key1", b"key2", b"key3"] {
+            bitcask
+                .put(key.to_vec(), b"value".to_vec())
+                .expect("Failed to put value");
+        }
+        assert!(bitcask.delete(b"key1").expect("Failed to delete key"));
+        assert!(bitcask.delete(b"key3").expect("Failed to delete key"));
+        bitcask
+            .put(b"key3".to_vec(), b"again".to_vec())
+            .expect("Failed to put value");
+        assert!(!bitcask.key_dir.contains_key(b"key1".as_slice()));
+        assert_eq!(bitcask.key_dir.len(), 2);
+        assert_eq!(bitcask.stats().unwrap().tombstones, 0);
+        assert!(!bitcask.delete(b"key1").expect("Failed to delete key"));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert!(matches!(
+            bitcask.get(b"key1"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert_eq!(bitcask.get(b"key3").unwrap(), b"again");
+        assert_eq!(bitcask.len(), 2);
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let path = fresh_dir("/tmp/test67");
+        let options = Options::builder()
+            .max_file_size(100)
+            .sync_policy(SyncPolicy::EveryWrite)
+            .compression(Compression::Snappy)
+            .build();
+        assert_eq!(options.max_file_size, 100);
+        assert_eq!(options.compression, Compression::Snappy);
+        assert_eq!(options.max_value_size, Options::default().max_value_size);
+
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        let first_file = bitcask.active_file_id;
+        for i in 0..3u8 {
+            bitcask
+                .put(vec![i], vec![i; 80])
+                .expect("Failed to put value");
+        }
+        assert_ne!(bitcask.active_file_id, first_file);
+        assert_eq!(bitcask.stats().unwrap().data_files, 3);
+    }
+
+    #[test]
+    fn test_clean_shutdown() {
+        let path = fresh_dir("/tmp/test68");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.was_clean_shutdown());
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+        assert!(Path::new(path).join(CLEAN_FILE).exists());
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.was_clean_shutdown());
+        assert!(!Path::new(path).join(CLEAN_FILE).exists());
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        // Crash: the process goes away without running any of the shutdown
+        // path, which also releases the lock.
+        bitcask.lock_file.take().unwrap().unlock().unwrap();
+        std::mem::forget(bitcask);
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(!bitcask.was_clean_shutdown());
+        assert!(bitcask.verify().unwrap().corrupt_records.is_empty());
+        assert_eq!(bitcask.get(b"key2").unwrap(), b"value2");
+        drop(bitcask);
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(bitcask.was_clean_shutdown());
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let path = fresh_dir("/tmp/test69");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let value = bitcask
+            .get_or_insert_with(b"key1", || b"computed".to_vec())
+            .expect("Failed to get or insert");
+        assert_eq!(value, b"computed");
+        let value = bitcask
+            .get_or_insert_with(b"key1", || panic!("key1 is already there"))
+            .expect("Failed to get or insert");
+        assert_eq!(value, b"computed");
+
+        bitcask.delete(b"key1").expect("Failed to delete key");
+        let value = bitcask
+            .get_or_insert_with(b"key1", || b"again".to_vec())
+            .expect("Failed to get or insert");
+        assert_eq!(value, b"again");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"again");
+    }
+
+    #[test]
+    fn test_prepared_merge() {
+        let path = fresh_dir("/tmp/test70");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for round in 0..3u8 {
+            for i in 0..20u8 {
+                bitcask
+                    .put(vec![i], vec![round, i])
+                    .expect("Failed to put value");
+            }
+        }
+
+        // Readers keep going against the old files while the output is
+        // written.
+        let bitcask_ref = &bitcask;
+        let prepared = std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(move || {
+                        for i in 0..20u8 {
+                            assert_eq!(bitcask_ref.get(&[i]).unwrap(), vec![2, i]);
+                        }
+                    })
+                })
+                .collect();
+            let prepared = bitcask_ref
+                .prepare_merge()
+                .expect("Failed to prepare merge");
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            prepared
+        });
+        assert_eq!(bitcask.get(&[5]).unwrap(), vec![2, 5]);
+
+        // Writes made before the commit survive it.
+        bitcask
+            .put(vec![1], b"newer".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(&[2]).expect("Failed to delete key");
+        bitcask
+            .put(vec![99], b"new".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .commit_merge(prepared)
+            .expect("Failed to commit merge");
+
+        let check = |bitcask: &Bitcask| {
+            assert_eq!(bitcask.get(&[1]).unwrap(), b"newer");
+            assert!(!bitcask.contains_key(&[2]));
+            assert_eq!(bitcask.get(&[99]).unwrap(), b"new");
+            assert_eq!(bitcask.get(&[5]).unwrap(), vec![2, 5]);
+            assert_eq!(bitcask.len(), 20);
+        };
+        check(&bitcask);
+        bitcask.close().expect("Failed to close Bitcask");
+        check(&Bitcask::open(path).expect("Failed to open Bitcask"));
+
+        // A merge that is never committed leaves nothing behind.
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        drop(bitcask.prepare_merge().expect("Failed to prepare merge"));
+        assert!(fs::read_dir(path).unwrap().all(|entry| entry
+            .unwrap()
+            .path()
+            .extension()
+            .is_none_or(|ext| ext != MERGING_EXT)));
+    }
+
+    #[test]
+    fn test_rebuild_all_hints() {
+        let path = fresh_dir("/tmp/test71");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let mut sealed = Vec::new();
+        for round in 0..3u8 {
+            for i in 0..10u8 {
+                bitcask
+                    .put(vec![i], vec![round, i])
+                    .expect("Failed to put value");
+            }
+            bitcask.delete(&[round]).expect("Failed to delete key");
+            sealed.push(bitcask.active_file_id);
+            bitcask.rotate().expect("Failed to rotate");
+        }
+        bitcask
+            .put(vec![0], b"active".to_vec())
+            .expect("Failed to put value");
+
+        // One hint left stale, the rest gone.
+        for file_id in &sealed {
+            let _ = fs::remove_file(Path::new(path).join(format!("{}.hint", file_id)));
+        }
+        let scanned = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+            .expect("Failed to build keydir")
+            .0;
+        fs::write(
+            Path::new(path).join(format!("{}.hint", sealed[0])),
+            file_header(HINT_MAGIC),
+        )
+        .unwrap();
+
+        bitcask
+            .rebuild_all_hints()
+            .expect("Failed to rebuild hints");
+        for file_id in &sealed {
+            assert!(Path::new(path).join(format!("{}.hint", file_id)).exists());
+        }
+        let active_hint = format!("{}.hint", bitcask.active_file_id);
+        assert!(!Path::new(path).join(active_hint).exists());
+        let hinted = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+            .expect("Failed to build keydir")
+            .0;
+        assert_eq!(hinted, scanned);
+    }
+
+    #[test]
+    fn test_value_reader() {
+        let path = fresh_dir("/tmp/test72");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let value: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        bitcask
+            .put(b"big".to_vec(), value.clone())
+            .expect("Failed to put value");
+
+        let read_back = |bitcask: &Bitcask| {
+            let mut reader = bitcask.value_reader(b"big").expect("Failed to open reader");
+            let mut chunk = [0u8; 333];
+            let mut out = Vec::new();
+            loop {
+                let n = reader.read(&mut chunk)?;
+                if n == 0 {
+                    return Ok::<_, io::Error>(out);
+                }
+                out.extend_from_slice(&chunk[..n]);
+            }
+        };
+        assert_eq!(read_back(&bitcask).unwrap(), value);
+        assert!(matches!(
+            bitcask.value_reader(b"missing"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+
+        // A flipped byte in the middle of the value fails the last read.
+        let kd = bitcask.key_dir[b"big".as_slice()].clone();
+        let filepath = Path::new(path).join(format!("{}.dat", kd.file_id));
+        let mut data = fs::read(&filepath).unwrap();
+        data[(kd.value_pos + 5000) as usize] ^= 0xFF;
+        fs::write(&filepath, data).unwrap();
+        let err = read_back(&bitcask).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_dead_bytes() {
+        let path = fresh_dir("/tmp/test73");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let record_size = HEADER_SIZE + 1 + 10;
+        for _ in 0..4 {
+            bitcask
+                .put(b"k".to_vec(), vec![7; 10])
+                .expect("Failed to put value");
+        }
+        assert_eq!(bitcask.stats().unwrap().dead_bytes, 3 * record_size);
+
+        // The delete kills the value and is dead itself, but only once.
+        bitcask.delete(b"k").expect("Failed to delete key");
+        let deleted = 4 * record_size + HEADER_SIZE + 1;
+        assert_eq!(bitcask.stats().unwrap().dead_bytes, deleted);
+        bitcask
+            .put(b"k".to_vec(), vec![7; 10])
+            .expect("Failed to put value");
+        assert_eq!(bitcask.stats().unwrap().dead_bytes, deleted);
+
+        bitcask.merge().expect("Failed to merge");
+        assert_eq!(bitcask.stats().unwrap().dead_bytes, 0);
+    }
+
+    #[test]
+    fn test_observer() {
+        let path = fresh_dir("/tmp/test74");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"before".to_vec(), b"unseen".to_vec())
+            .expect("Failed to put value");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        bitcask.set_observer(Box::new(move |event| seen.lock().unwrap().push(event)));
+
+        bitcask
+            .put(b"a".to_vec(), b"1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put_batch(vec![
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"a".to_vec(), b"3".to_vec()),
+            ])
+            .expect("Failed to put batch");
+        bitcask.delete(b"a").expect("Failed to delete key");
+        // Nothing is written for a key that isn't there, so nothing is seen.
+        bitcask.delete(b"a").expect("Failed to delete key");
+
+        let put = |key: &[u8], value: &[u8]| Event::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                put(b"a", b"1"),
+                put(b"b", b"2"),
+                put(b"a", b"3"),
+                Event::Delete { key: b"a".to_vec() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncated_after_indexing() {
+        let path = fresh_dir("/tmp/test75");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key1".to_vec(), b"value1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"key2".to_vec(), b"value2".to_vec())
+            .expect("Failed to put value");
+        let kd = bitcask.key_dir[b"key2".as_slice()].clone();
+
+        // The key dir now points past the end of the file.
+        let filepath = Path::new(path).join(format!("{}.dat", kd.file_id));
+        let file = fs::OpenOptions::new().write(true).open(filepath).unwrap();
+        file.set_len(kd.value_pos + 2).unwrap();
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1");
+        let corrupt = |result| {
+            matches!(result, Err(BitcaskError::CorruptRecord { file_id, value_pos })
+                if file_id == kd.file_id && value_pos == kd.value_pos)
+        };
+        assert!(corrupt(bitcask.get(b"key2")));
+        assert!(corrupt(bitcask.value_reader(b"key2").map(|_| Vec::new())));
+    }
+
+    #[test]
+    fn test_merge_step() {
+        let fill = |path| {
+            let options = Options::builder().max_file_size(512).build();
+            let mut bitcask =
+                Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+            for round in 0..3u8 {
+                for i in 0..30u8 {
+                    bitcask
+                        .put(vec![i], vec![round; 20])
+                        .expect("Failed to put value");
+                }
+            }
+            for i in (0..30u8).step_by(3) {
+                bitcask.delete(&[i]).expect("Failed to delete key");
+            }
+            bitcask.rotate().expect("Failed to rotate");
+            bitcask
+        };
+        let contents = |bitcask: &Bitcask| {
+            let mut pairs: Vec<_> = bitcask.iter().map(Result::unwrap).collect();
+            pairs.sort();
+            pairs
+        };
+
+        let whole_path = fresh_dir("/tmp/test76");
+        let mut whole = fill(whole_path);
+        whole.merge().expect("Failed to merge");
+
+        let path = fresh_dir("/tmp/test77");
+        let mut stepped = fill(path);
+        let mut steps = 1;
+        while stepped.merge_step().expect("Failed to merge step") {
+            steps += 1;
+        }
+        assert!(steps > 1);
+        assert_eq!(contents(&stepped), contents(&whole));
+        assert_eq!(stepped.stats().unwrap().dead_bytes, 0);
+        assert!(!stepped.key_dir.values().any(|kd| kd.tombstone));
+
+        stepped.close().expect("Failed to close Bitcask");
+        let reopened = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(contents(&reopened), contents(&whole));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let path = fresh_dir("/tmp/test78");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        // Absent, as expected.
+        assert!(bitcask
+            .compare_and_swap(b"lock", None, b"v1".to_vec())
+            .expect("Failed to swap"));
+        // Present, but expected absent.
+        assert!(!bitcask
+            .compare_and_swap(b"lock", None, b"other".to_vec())
+            .expect("Failed to swap"));
+        // A stale expectation writes nothing.
+        let total_bytes = bitcask.total_bytes;
+        assert!(!bitcask
+            .compare_and_swap(b"lock", Some(b"v0"), b"v2".to_vec())
+            .expect("Failed to swap"));
+        assert_eq!(bitcask.total_bytes, total_bytes);
+        assert_eq!(bitcask.get(b"lock").unwrap(), b"v1");
+
+        assert!(bitcask
+            .compare_and_swap(b"lock", Some(b"v1"), b"v2".to_vec())
+            .expect("Failed to swap"));
+        assert_eq!(bitcask.get(b"lock").unwrap(), b"v2");
+
+        // Expected present, but absent.
+        bitcask.delete(b"lock").expect("Failed to delete key");
+        assert!(!bitcask
+            .compare_and_swap(b"lock", Some(b"v2"), b"v3".to_vec())
+            .expect("Failed to swap"));
+        assert!(!bitcask.contains_key(b"lock"));
+    }
+
+    #[test]
+    fn test_hint_on_rotate() {
+        let path = fresh_dir("/tmp/test79");
+        let options = Options::builder().max_file_size(256).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..40u8 {
+            bitcask
+                .put(vec![i % 15], vec![i; 20])
+                .expect("Failed to put value");
+        }
+        bitcask.delete(&[3]).expect("Failed to delete key");
+        let active_file_id = bitcask.active_file_id;
+        // Reopening leaves deleted keys out.
+        let mut expected = bitcask.key_dir.clone();
+        expected.retain(|_, kd| !kd.tombstone);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut sealed = 0;
+        for entry in fs::read_dir(path).unwrap() {
+            let filepath = entry.unwrap().path();
+            if filepath.extension().is_some_and(|ext| ext == "dat") {
+                let hinted = filepath.with_extension("hint").exists();
+                assert_eq!(hinted, get_file_id(&filepath) != Some(active_file_id));
+                sealed += hinted as usize;
+            }
+        }
+        assert!(sealed > 2);
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.key_dir, expected);
+    }
+
+    #[test]
+    fn test_hashed_keys() {
+        let path = fresh_dir("/tmp/test80");
+        let options = Options::builder().hashed_keys(true).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        // Every key collides.
+        bitcask.hashed.as_mut().unwrap().hasher = |_| 7;
+        for (key, value) in [(b"a", b"1"), (b"b", b"2"), (b"c", b"3"), (b"b", b"4")] {
+            bitcask
+                .put(key.to_vec(), value.to_vec())
+                .expect("Failed to put value");
+        }
+        bitcask.delete(b"a").expect("Failed to delete key");
+        let check = |bitcask: &Bitcask| {
+            assert!(matches!(bitcask.get(b"a"), Err(BitcaskError::KeyNotFound)));
+            assert_eq!(bitcask.get(b"b").unwrap(), b"4");
+            assert_eq!(bitcask.get(b"c").unwrap(), b"3");
+            assert!(!bitcask.contains_key(b"d"));
+            assert_eq!(bitcask.len(), 2);
+        };
+        check(&bitcask);
+        let hashed = bitcask.hashed.as_ref().unwrap();
+        assert_eq!(hashed.slots.len(), 1);
+        assert!(bitcask.key_dir.is_empty());
+        assert!(matches!(
+            bitcask.prepare_merge(),
+            Err(BitcaskError::HashedKeys)
+        ));
+
+        bitcask.merge().expect("Failed to merge");
+        check(&bitcask);
+        assert_eq!(bitcask.stats().unwrap().reclaimable_bytes, 0);
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        check(&bitcask);
+        assert!(bitcask.hashed.as_ref().unwrap().collided.is_empty());
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let path = fresh_dir("/tmp/test81");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let invalid =
+            |result: Result<(), BitcaskError>| matches!(result, Err(BitcaskError::InvalidKey));
+        assert!(invalid(bitcask.put(Vec::new(), b"v".to_vec()).map(drop)));
+        assert!(invalid(
+            bitcask
+                .put_with_ttl(Vec::new(), b"v".to_vec(), Duration::from_secs(1))
+                .map(drop)
+        ));
+        assert!(invalid(bitcask.delete(b"").map(drop)));
+        assert!(invalid(bitcask.put_batch(vec![
+            (b"k".to_vec(), b"v".to_vec()),
+            (Vec::new(), b"v".to_vec()),
+        ])));
+        // The batch is rejected whole.
+        assert!(bitcask.is_empty());
+        assert_eq!(bitcask.writer_pos, FILE_HEADER_SIZE);
+        assert!(invalid(
+            Bitcask::import(fresh_dir("/tmp/test82"), vec![(Vec::new(), b"v".to_vec())]).map(drop)
+        ));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let path = fresh_dir("/tmp/test83");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.metrics(), Metrics::default());
+        bitcask
+            .put(b"a".to_vec(), b"1".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put_batch(vec![
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"3".to_vec()),
+            ])
+            .expect("Failed to put batch");
+        bitcask.get(b"a").unwrap();
+        bitcask.get(b"a").unwrap();
+        assert!(bitcask.get(b"missing").is_err());
+        let _ = bitcask.get_many(&[b"b", b"c"]);
+        bitcask.delete(b"a").expect("Failed to delete key");
+        bitcask.merge().expect("Failed to merge");
+
+        let record_size = HEADER_SIZE + 2;
+        let metrics = bitcask.metrics();
+        assert_eq!(
+            metrics,
+            Metrics {
+                puts: 3,
+                gets: 5,
+                deletes: 1,
+                // All of it is still in the active file: two gets, the
+                // get_many and the merge copying two live values. A delete
+                // doesn't read the old value back.
+                handle_hits: 5,
+                handle_misses: 0,
+                merges: 1,
+                bytes_written: 3 * record_size + HEADER_SIZE + 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_active_file_reads() {
+        let path = fresh_dir("/tmp/test84");
+        let options = Options::builder().write_buffer_size(4096).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..10u8 {
+            bitcask
+                .put(vec![i], vec![i; 100])
+                .expect("Failed to put value");
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![i; 100]);
+            // Past the buffer, so the read goes to the file.
+            bitcask.flush().expect("Failed to flush");
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![i; 100]);
+        }
+        let sealed_id = bitcask.active_file_id;
+        bitcask.rotate().expect("Failed to rotate");
+        assert_eq!(bitcask.get(&[3]).unwrap(), vec![3; 100]);
+
+        assert_eq!(bitcask.metrics().handle_misses, 0);
+        let handles = bitcask.read_handles.lock().unwrap();
+        assert_eq!(handles.keys().collect::<Vec<_>>(), vec![&sealed_id]);
+    }
+
+    #[test]
+    fn test_merge_dry_run() {
+        let path = fresh_dir("/tmp/test85");
+        let options = Options::builder().max_file_size(300).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for round in 0..4u8 {
+            for i in 0..12u8 {
+                bitcask
+                    .put(vec![i], vec![round; 30])
+                    .expect("Failed to put value");
+            }
+        }
+        bitcask.delete(&[0]).expect("Failed to delete key");
+        bitcask.delete(&[1]).expect("Failed to delete key");
+
+        let data_files = || -> Vec<u64> {
+            let mut ids: Vec<u64> = fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|filepath| filepath.extension().is_some_and(|ext| ext == "dat"))
+                .filter_map(|filepath| get_file_id(&filepath))
+                .collect();
+            ids.sort();
+            ids
+        };
+        let before = data_files();
+        let total_before = bitcask.stats().unwrap().total_bytes;
+        let plan = bitcask.merge_dry_run().expect("Failed to plan merge");
+        assert_eq!(plan.files_removed, before);
+        assert_eq!(plan.live_records, 10);
+        // Planning wrote nothing.
+        assert_eq!(data_files(), before);
+        assert_eq!(bitcask.stats().unwrap().total_bytes, total_before);
+
+        bitcask.merge().expect("Failed to merge");
+        let after = data_files();
+        assert!(after.iter().all(|id| !before.contains(id)));
+        assert_eq!(bitcask.len() as u64, plan.live_records);
+        let total_after = bitcask.stats().unwrap().total_bytes;
+        assert_eq!(total_before - total_after, plan.reclaimed_bytes);
+    }
+
+    #[test]
+    fn test_read_at() {
+        let path = fresh_dir("/tmp/test86");
+        let options = Options::builder().write_buffer_size(128).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"flushed".to_vec(), vec![1; 100])
+            .expect("Failed to put value");
+        bitcask
+            .put(b"buffered".to_vec(), b"still in the buffer".to_vec())
+            .expect("Failed to put value");
+        assert!(!bitcask.active_file.as_ref().unwrap().buffer().is_empty());
+        for key in [b"flushed".as_slice(), b"buffered"] {
+            let entry = bitcask.get_entry(key).expect("Failed to get entry");
+            let value_size = entry.value.len() as u64;
+            let raw = bitcask
+                .read_at(entry.file_id, entry.value_pos, value_size)
+                .expect("Failed to read");
+            assert_eq!(raw, entry.value);
+            assert!(matches!(
+                bitcask.read_at(entry.file_id, entry.value_pos, value_size + 1000),
+                Err(BitcaskError::CorruptRecord { .. })
+            ));
+        }
+
+        // A range straddling the buffer takes each part from where it is.
+        let start = bitcask.get_entry(b"flushed").unwrap().value_pos + 90;
+        let straddling = bitcask
+            .read_at(bitcask.active_file_id, start, 10 + HEADER_SIZE + 8)
+            .expect("Failed to read");
+        assert_eq!(&straddling[..10], &[1; 10]);
+        assert_eq!(&straddling[10 + HEADER_SIZE as usize..], b"buffered");
+    }
+
+    #[test]
+    fn test_record_alignment() {
+        let path = fresh_dir("/tmp/test87");
+        let options = Options::builder().record_alignment(512).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        // 457 bytes of value leave a gap too short for a padding header.
+        let values: Vec<Vec<u8>> = [10, 457, 600, 0, 2000]
+            .iter()
+            .map(|&len| vec![len as u8; len])
+            .collect();
+        for (i, value) in values.iter().enumerate() {
+            bitcask
+                .put(vec![b'k', i as u8], value.clone())
+                .expect("Failed to put value");
+        }
+        bitcask
+            .put_batch(vec![
+                (b"b1".to_vec(), vec![1; 30]),
+                (b"b2".to_vec(), vec![2; 30]),
+            ])
+            .expect("Failed to put batch");
+        bitcask.delete(&[b'k', 0]).expect("Failed to delete key");
+        let aligned = |bitcask: &Bitcask| {
+            bitcask.key_dir.iter().all(|(key, kd)| {
+                (kd.value_pos - HEADER_SIZE - key.len() as u64).is_multiple_of(512)
+            })
+        };
+        assert!(aligned(&bitcask));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert!(aligned(&bitcask));
+        for (i, value) in values.iter().enumerate().skip(1) {
+            assert_eq!(&bitcask.get(&[b'k', i as u8]).unwrap(), value);
+        }
+        assert!(!bitcask.contains_key(&[b'k', 0]));
+        assert_eq!(bitcask.get(b"b2").unwrap(), vec![2; 30]);
+        let report = bitcask.verify().expect("Failed to verify");
+        assert_eq!(report.total_records, 8);
+        assert!(report.corrupt_records.is_empty());
+
+        bitcask.merge().expect("Failed to merge");
+        assert!(aligned(&bitcask));
+        assert_eq!(bitcask.get(&[b'k', 4]).unwrap(), values[4]);
+        // Padding is overhead, not something a merge could reclaim.
+        assert_eq!(bitcask.dead_ratio(), 0.0);
+
+        // Reads don't depend on the alignment files were written with.
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(&[b'k', 2]).unwrap(), values[2]);
+    }
+
+    #[test]
+    fn test_scan_log() {
+        let path = fresh_dir("/tmp/test88");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key".to_vec(), b"first".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        // The rest goes into a second data file, and stays buffered.
+        let options = Options::builder().write_buffer_size(4096).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key".to_vec(), b"second".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"key").expect("Failed to delete key");
+        let log: Vec<LogRecord> = bitcask
+            .scan_log()
+            .collect::<Result<_, _>>()
+            .expect("Failed to scan log");
+        let summary: Vec<(&[u8], &[u8], bool)> = log
+            .iter()
+            .map(|record| {
+                (
+                    record.key.as_slice(),
+                    record.value.as_slice(),
+                    record.tombstone,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (b"key".as_slice(), b"first".as_slice(), false),
+                (b"key", b"second", false),
+                (b"key", b"", true),
+            ]
+        );
+        assert!(log.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert_eq!(bitcask.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_open_creates_missing_dir() {
+        let path = fresh_dir("/tmp/test89");
+        let options = Options::builder().shards(2).build();
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert!(Path::new(path).join("001").is_dir());
+        bitcask.close().expect("Failed to close Bitcask");
+        Bitcask::open(fresh_dir("/tmp/test89")).expect("Failed to open Bitcask");
+        assert!(Path::new(path).is_dir());
+    }
+
+    #[test]
+    fn test_open_without_create_if_missing() {
+        let path = fresh_dir("/tmp/test90");
+        let options = Options::builder().create_if_missing(false).build();
+        assert!(matches!(
+            Bitcask::open_with_options(path, options.clone()),
+            Err(BitcaskError::DirNotFound)
+        ));
+        assert!(!Path::new(path).exists());
+
+        fs::create_dir_all(path).unwrap();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"key".to_vec(), b"value".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.get(b"key").unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_corrupt_hint_falls_back_to_data() {
+        let path = fresh_dir("/tmp/test91");
+        let options = Options::builder().drop_deleted_keys(true).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..20u8 {
+            bitcask
+                .put(vec![b'k', i], vec![i; 50])
+                .expect("Failed to put value");
+        }
+        bitcask.delete(&[b'k', 3]).expect("Failed to delete key");
+        let file_id = bitcask.active_file_id;
+        bitcask.rotate().expect("Failed to rotate");
+        bitcask.close().expect("Failed to close Bitcask");
+        let scanned = {
+            let hint_filepath = Path::new(path).join(format!("{}.hint", file_id));
+            let hint = fs::read(&hint_filepath).unwrap();
+            fs::remove_file(&hint_filepath).unwrap();
+            let scanned = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+                .expect("Failed to build keydir")
+                .0;
+            fs::write(&hint_filepath, &hint).unwrap();
+            scanned
+        };
+        assert_eq!(scanned.len(), 19);
+
+        let hint_filepath = Path::new(path).join(format!("{}.hint", file_id));
+        let original = fs::read(&hint_filepath).unwrap();
+        // A value position pointed elsewhere, a hint cut short, and its
+        // count off by one.
+        let mut moved = original.clone();
+        moved[FILE_HEADER_SIZE as usize + 8 * 4] ^= 0x40;
+        let cut = original[..original.len() - 30].to_vec();
+        let mut miscounted = original.clone();
+        let count_pos = original.len() - HINT_TRAILER_SIZE as usize;
+        miscounted[count_pos] ^= 1;
+        for hint in [moved, cut, miscounted] {
+            fs::write(&hint_filepath, &hint).unwrap();
+            let key_dir = build_keydir(path, Layout::default(), RecoveryMode::Strict, None, now())
+                .expect("Failed to build keydir")
+                .0;
+            assert_eq!(key_dir, scanned);
+        }
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(&[b'k', 7]).unwrap(), vec![7; 50]);
+        assert!(!bitcask.contains_key(&[b'k', 3]));
+    }
+
+    #[test]
+    fn test_meta() {
+        let path = fresh_dir("/tmp/test92");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get_meta("schema"), None);
+        bitcask
+            .set_meta("schema", b"1")
+            .expect("Failed to set meta");
+        bitcask
+            .set_meta("schema", b"2")
+            .expect("Failed to set meta");
+        bitcask.set_meta("owner", b"").expect("Failed to set meta");
+        bitcask
+            .put(b"key".to_vec(), b"value".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.list_keys().unwrap(), vec![&b"key".to_vec()]);
+        bitcask.merge().expect("Failed to merge");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get_meta("schema"), Some(b"2".as_slice()));
+        assert_eq!(bitcask.get_meta("owner"), Some(b"".as_slice()));
+        assert_eq!(bitcask.list_keys().unwrap(), vec![&b"key".to_vec()]);
+        assert_eq!(bitcask.iter().count(), 1);
+
+        let copy = fresh_dir("/tmp/test92-copy");
+        bitcask.merge_into(copy).expect("Failed to merge");
+        let bitcask = Bitcask::open_read_only(copy).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get_meta("schema"), Some(b"2".as_slice()));
+    }
+
+    #[test]
+    fn test_error_display() {
+        use std::error::Error;
+
+        let cases = [
+            (BitcaskError::InvalidFileFormat, "invalid file format"),
+            (BitcaskError::KeyNotFound, "key not found"),
+            (BitcaskError::DirNotFound, "directory not found"),
+            (
+                BitcaskError::AlreadyLocked,
+                "directory is locked by another writer",
+            ),
+            (
+                BitcaskError::ActiveFile,
+                "operation not allowed on the active file",
+            ),
+            (BitcaskError::ReadOnly, "store is read-only"),
+            (
+                BitcaskError::CorruptRecord {
+                    file_id: 3,
+                    value_pos: 120,
+                },
+                "corrupt record in data file 3 at value position 120",
+            ),
+            (BitcaskError::ValueTooLarge, "value too large"),
+            (BitcaskError::Decode, "value failed to decode"),
+            (
+                BitcaskError::UnsupportedVersion(9),
+                "unsupported format version 9",
+            ),
+            (BitcaskError::Decryption, "value failed to decrypt"),
+            (BitcaskError::HashedKeys, "not supported with hashed keys"),
+            (BitcaskError::InvalidKey, "invalid key"),
+            (BitcaskError::Full, "store is full"),
+            (BitcaskError::Timeout, "operation timed out"),
+            (
+                BitcaskError::DuplicateFileId(7),
+                "more than one data file with id 7",
+            ),
+        ];
+        for (error, message) in cases {
+            assert_eq!(error.to_string(), message);
+            assert!(error.source().is_none());
+        }
+
+        let error = BitcaskError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert_eq!(error.to_string(), "I/O error");
+        let source = error.source().expect("Io should have a source");
+        assert_eq!(source.to_string(), "gone");
+        // Works with `?` into a boxed error.
+        let boxed = || -> Result<(), Box<dyn Error>> {
+            Err::<(), _>(error)?;
+            Ok(())
+        };
+        let boxed = boxed().unwrap_err();
+        assert!(boxed.downcast_ref::<BitcaskError>().is_some());
+    }
+
+    #[test]
+    fn test_keep_versions() {
+        let path = fresh_dir("/tmp/test93");
+        let options = Options::builder().keep_versions(2).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        for version in 1..=5u8 {
+            bitcask
+                .put(b"key".to_vec(), vec![version])
+                .expect("Failed to put value");
+            if version == 3 {
+                bitcask.rotate().expect("Failed to rotate");
+            }
+        }
+        // A deleted key keeps nothing, or reopening would bring it back.
+        for version in 1..=3u8 {
+            bitcask
+                .put(b"gone".to_vec(), vec![version])
+                .expect("Failed to put value");
+        }
+        bitcask.delete(b"gone").expect("Failed to delete key");
+        bitcask
+            .put(b"single".to_vec(), b"only".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.merge_dry_run().unwrap().live_records, 3);
+        bitcask.merge().expect("Failed to merge");
+
+        let log = |bitcask: &Bitcask| -> Vec<(Vec<u8>, Vec<u8>)> {
+            bitcask
+                .scan_log()
+                .map(|record| record.map(|record| (record.key, record.value)))
+                .collect::<Result<_, _>>()
+                .expect("Failed to scan log")
+        };
+        let mut versions = log(&bitcask);
+        versions.sort();
+        assert_eq!(
+            versions,
+            vec![
+                (b"key".to_vec(), vec![4]),
+                (b"key".to_vec(), vec![5]),
+                (b"single".to_vec(), b"only".to_vec()),
+            ]
+        );
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key").unwrap(), vec![5]);
+        assert!(!bitcask.contains_key(b"gone"));
+        // Merging again keeps the same two.
+        bitcask.merge().expect("Failed to merge");
+        let key_versions: Vec<_> = log(&bitcask)
+            .into_iter()
+            .filter(|(key, _)| key == b"key")
+            .collect();
+        assert_eq!(
+            key_versions,
+            vec![(b"key".to_vec(), vec![4]), (b"key".to_vec(), vec![5])]
+        );
+    }
+
+    #[test]
+    fn test_size_on_disk() {
+        let path = fresh_dir("/tmp/test94");
+        let options = Options::builder().write_buffer_size(1 << 16).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for i in 0..10u8 {
+            bitcask
+                .put(vec![b'k', i], vec![i; 100])
+                .expect("Failed to put value");
+        }
+        let record_size = HEADER_SIZE + 2 + 100;
+        let sealed = bitcask.active_file_id;
+        bitcask.rotate().expect("Failed to rotate");
+        bitcask
+            .put(b"buffered".to_vec(), vec![0; 50])
+            .expect("Failed to put value");
+        let buffered_size = HEADER_SIZE + 8 + 50;
+
+        assert_eq!(
+            bitcask.file_sizes().unwrap(),
+            vec![
+                (sealed, FILE_HEADER_SIZE + 10 * record_size),
+                (bitcask.active_file_id, FILE_HEADER_SIZE + buffered_size),
+            ]
+        );
+        // The hint written on rotation counts too.
+        let hint_size = fs::metadata(Path::new(path).join(format!("{}.hint", sealed)))
+            .unwrap()
+            .len();
+        let data_size = 2 * FILE_HEADER_SIZE + 10 * record_size + buffered_size;
+        assert_eq!(bitcask.size_on_disk().unwrap(), data_size + hint_size);
+        assert_eq!(
+            hint_size,
+            FILE_HEADER_SIZE + 10 * (8 * 5 + 2) + HINT_TRAILER_SIZE
+        );
+    }
+
+    #[test]
+    fn test_mock_clock_drives_ttl() {
+        let path = fresh_dir("/tmp/test95");
+        let clock = MockClock::new(5_000_000);
+        let options = Options::builder().clock(clock.clone()).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        bitcask
+            .put_with_ttl(
+                b"session".to_vec(),
+                b"token".to_vec(),
+                Duration::from_secs(10),
+            )
+            .expect("Failed to put value");
+        bitcask
+            .put(b"config".to_vec(), b"v1".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(bitcask.get_entry(b"config").unwrap().timestamp, 5_000_000);
+
+        clock.advance(Duration::from_millis(9_999));
+        assert_eq!(bitcask.get(b"session").unwrap(), b"token");
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        assert!(bitcask.contains_key(b"session"));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            bitcask.get(b"session"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        assert_eq!(bitcask.len(), 1);
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert!(!bitcask.contains_key(b"session"));
+        assert_eq!(bitcask.get(b"config").unwrap(), b"v1");
+    }
+
+    #[test]
+    fn test_failed_merge_keeps_serving() {
+        let path = fresh_dir("/tmp/test96");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for i in 0..10u8 {
+            bitcask
+                .put(vec![b'k', i], vec![i; 20])
+                .expect("Failed to put value");
+        }
+        bitcask.rotate().expect("Failed to rotate");
+        let check = |bitcask: &Bitcask, changed: u8| {
+            for i in 0..10u8 {
+                let expected = if i == 0 {
+                    vec![changed; 20]
+                } else {
+                    vec![i; 20]
+                };
+                assert_eq!(bitcask.get(&[b'k', i]).unwrap(), expected);
+            }
+        };
+
+        // Output that can't be published.
+        let prepared = bitcask.prepare_merge().expect("Failed to prepare merge");
+        bitcask
+            .put(vec![b'k', 0], vec![100; 20])
+            .expect("Failed to put value");
+        for file_id in &prepared.file_ids {
+            fs::remove_file(prepared.temp_path(*file_id, "dat")).unwrap();
+        }
+        assert!(matches!(
+            bitcask.commit_merge(prepared),
+            Err(BitcaskError::Io(_))
+        ));
+        check(&bitcask, 100);
+
+        // A store file that can't be deleted. Whichever old files went
+        // before it, reads already go to the merged output.
+        let stuck = Path::new(path).join("1.hint");
+        fs::create_dir(&stuck).unwrap();
+        assert!(matches!(bitcask.merge(), Err(BitcaskError::Io(_))));
+        check(&bitcask, 100);
+        bitcask
+            .put(vec![b'k', 0], vec![200; 20])
+            .expect("Failed to put value");
+
+        fs::remove_dir(&stuck).unwrap();
+        bitcask.merge().expect("Failed to merge");
+        check(&bitcask, 200);
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        check(&bitcask, 200);
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let path = fresh_dir("/tmp/test97");
+        let options = Options::builder().max_keys(3).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        for key in [b"a", b"b", b"c"] {
+            bitcask
+                .put(key.to_vec(), b"value".to_vec())
+                .expect("Failed to put value");
+        }
+        assert!(matches!(
+            bitcask.put(b"d".to_vec(), b"value".to_vec()),
+            Err(BitcaskError::Full)
+        ));
+        assert!(matches!(
+            bitcask.put_batch(vec![
+                (b"a".to_vec(), b"new".to_vec()),
+                (b"e".to_vec(), b"value".to_vec()),
+            ]),
+            Err(BitcaskError::Full)
+        ));
+        assert_eq!(bitcask.get(b"a").unwrap(), b"value");
+        assert!(!bitcask.contains_key(b"d"));
+
+        // Overwrites don't need room.
+        bitcask
+            .put(b"a".to_vec(), b"changed".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put_batch(vec![
+                (b"b".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+            ])
+            .expect("Failed to put batch");
+        assert_eq!(bitcask.get(b"a").unwrap(), b"changed");
+        assert_eq!(bitcask.get(b"b").unwrap(), b"2");
+
+        // A deleted key holds its place until a merge.
+        bitcask.delete(b"c").expect("Failed to delete key");
+        assert!(matches!(
+            bitcask.put(b"d".to_vec(), b"value".to_vec()),
+            Err(BitcaskError::Full)
+        ));
+        bitcask.merge().expect("Failed to merge");
+        bitcask
+            .put(b"d".to_vec(), b"value".to_vec())
+            .expect("Failed to put value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert!(matches!(
+            bitcask.put(b"e".to_vec(), b"value".to_vec()),
+            Err(BitcaskError::Full)
+        ));
+    }
+
+    #[test]
+    fn test_compact_file() {
+        let path = fresh_dir("/tmp/test98");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask.put(vec![9], vec![9; 100]).unwrap();
+        bitcask.rotate().unwrap();
+        for i in 0..10u8 {
+            bitcask.put(vec![i], vec![i; 100]).unwrap();
+        }
+        // Still shadowing the value in the file before.
+        bitcask.delete(&[9]).unwrap();
+        let cold = bitcask.active_file_id;
+        bitcask.rotate().unwrap();
+        for i in 0..8u8 {
+            bitcask.put(vec![i], vec![i + 100; 100]).unwrap();
+        }
+        bitcask.rotate().unwrap();
+
+        assert!(matches!(
+            bitcask.compact_file(bitcask.active_file_id),
+            Err(BitcaskError::ActiveFile)
+        ));
+        bitcask.compact_file(cold).expect("Failed to compact file");
+        assert!(!bitcask
+            .layout
+            .path(&bitcask.data_path, cold, "dat")
+            .exists());
+        for i in 0..8u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![i + 100; 100]);
+        }
+        assert_eq!(bitcask.get(&[8]).unwrap(), vec![8; 100]);
+        assert!(matches!(bitcask.get(&[9]), Err(BitcaskError::KeyNotFound)));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 9);
+        assert_eq!(bitcask.get(&[8]).unwrap(), vec![8; 100]);
+        assert_eq!(bitcask.get(&[3]).unwrap(), vec![103; 100]);
+        assert!(matches!(bitcask.get(&[9]), Err(BitcaskError::KeyNotFound)));
+    }
+
+    #[test]
+    fn test_debug_keydir() {
+        let path = fresh_dir("/tmp/test99");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask.put(b"a".to_vec(), b"one".to_vec()).unwrap();
+        bitcask.put(b"b".to_vec(), b"three".to_vec()).unwrap();
+
+        let dump = bitcask.debug_keydir();
+        assert_eq!(dump.len(), 2);
+        let (key, file_id, value_size, value_pos, timestamp) = &dump[0];
+        assert_eq!(key, b"a");
+        assert_eq!(*file_id, bitcask.active_file_id);
+        assert_eq!(*value_size, 3);
+        assert_eq!(*value_pos, FILE_HEADER_SIZE + HEADER_SIZE + 1);
+        assert!(*timestamp > 0);
+        let (key, file_id, value_size, value_pos, _) = &dump[1];
+        assert_eq!(key, b"b");
+        assert_eq!(*file_id, bitcask.active_file_id);
+        assert_eq!(*value_size, 5);
+        assert_eq!(*value_pos, FILE_HEADER_SIZE + 2 * HEADER_SIZE + 1 + 3 + 1);
+        assert_eq!(
+            bitcask.read_at(*file_id, *value_pos, *value_size).unwrap(),
+            b"three"
+        );
+    }
+
+    #[test]
+    fn test_get_into() {
+        let path = fresh_dir("/tmp/test100");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask.put(b"long".to_vec(), vec![7; 1000]).unwrap();
+        bitcask.put(b"short".to_vec(), b"hi".to_vec()).unwrap();
+        bitcask.sync().unwrap();
+        bitcask
+            .put(b"buffered".to_vec(), b"pending".to_vec())
+            .unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(bitcask.get_into(b"long", &mut buf).unwrap(), 1000);
+        assert_eq!(buf, vec![7; 1000]);
+        let capacity = buf.capacity();
+        assert_eq!(bitcask.get_into(b"short", &mut buf).unwrap(), 2);
+        assert_eq!(buf, b"hi");
+        assert_eq!(bitcask.get_into(b"buffered", &mut buf).unwrap(), 7);
+        assert_eq!(buf, b"pending");
+        assert_eq!(buf.capacity(), capacity);
+        assert!(matches!(
+            bitcask.get_into(b"missing", &mut buf),
+            Err(BitcaskError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_partial_tail_truncated_on_open() {
+        let path = fresh_dir("/tmp/test101");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        bitcask.sync().unwrap();
+        let file_id = bitcask.active_file_id;
+        let filepath = bitcask.layout.path(&bitcask.data_path, file_id, "dat");
+        let valid_len = fs::metadata(&filepath).unwrap().len();
+        // A crash partway through the second record, without a clean close.
+        let record =
+            DataFileEntry::with_timestamp(b"key2".to_vec(), b"value2".to_vec(), now(), 0, 0);
+        let mut file = fs::OpenOptions::new().append(true).open(&filepath).unwrap();
+        file.write_all(&record.to_bytes()[..HEADER_SIZE as usize + 2])
+            .unwrap();
+        drop(file);
+        drop(bitcask);
+        fs::remove_file(Path::new(path).join(CLEAN_FILE)).unwrap_or_default();
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert!(!bitcask.was_clean_shutdown());
+        assert_eq!(fs::metadata(&filepath).unwrap().len(), valid_len);
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"value1");
+        assert!(matches!(
+            bitcask.get(b"key2"),
+            Err(BitcaskError::KeyNotFound)
+        ));
+        bitcask.put(b"key3".to_vec(), b"value3".to_vec()).unwrap();
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 2);
+        assert_eq!(bitcask.get(b"key3").unwrap(), b"value3");
+        assert!(bitcask.verify().unwrap().corrupt_records.is_empty());
+    }
+
+    #[test]
+    fn test_values() {
+        let path = fresh_dir("/tmp/test102");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for i in 1..=10u8 {
+            bitcask.put(vec![i], vec![i; i as usize]).unwrap();
+        }
+        bitcask.delete(&[10]).unwrap();
+        bitcask.put(vec![1], vec![0; 5]).unwrap();
+
+        let total: usize = bitcask.values().map(|value| value.unwrap().len()).sum();
+        assert_eq!(total, (2..=9).sum::<usize>() + 5);
+        assert_eq!(bitcask.values().count(), 9);
+    }
+
+    #[test]
+    fn test_interval_flusher() {
+        let path = fresh_dir("/tmp/test103");
+        let options = Options::builder()
+            .sync_policy(SyncPolicy::Interval(Duration::from_millis(10)))
+            .build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask.put(b"key1".to_vec(), b"durable".to_vec()).unwrap();
+        bitcask.rotate().unwrap();
+        bitcask
+            .put(b"key2".to_vec(), b"after rotate".to_vec())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let flusher = bitcask.flusher.as_ref().unwrap();
+        assert!(flusher.syncs() > 0);
+        // Already out of the write buffer, for the flusher to sync.
+        let filepath = bitcask
+            .layout
+            .path(&bitcask.data_path, bitcask.active_file_id, "dat");
+        let on_disk = fs::read(filepath).unwrap();
+        assert!(on_disk.ends_with(b"after rotate"));
+        bitcask.close().expect("Failed to close Bitcask");
+
+        // Closing doesn't wait out the interval.
+        let options = Options::builder()
+            .sync_policy(SyncPolicy::Interval(Duration::from_secs(3600)))
+            .build();
+        let bitcask = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"key1").unwrap(), b"durable");
+        let started = std::time::Instant::now();
+        bitcask.close().expect("Failed to close Bitcask");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_keys_since() {
+        let path = fresh_dir("/tmp/test104");
+        let clock = MockClock::new(1_000);
+        let options = Options::builder().clock(clock.clone()).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for key in [b"a", b"b", b"c"] {
+            bitcask.put(key.to_vec(), b"old".to_vec()).unwrap();
+        }
+        let watermark = clock.now();
+
+        clock.advance(Duration::from_millis(1));
+        bitcask.put(b"b".to_vec(), b"new".to_vec()).unwrap();
+        bitcask.put(b"d".to_vec(), b"new".to_vec()).unwrap();
+        bitcask.put(b"e".to_vec(), b"new".to_vec()).unwrap();
+        bitcask.delete(b"e").unwrap();
+
+        let mut keys: Vec<_> = bitcask.keys_since(watermark).collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"b".as_slice(), b"d"]);
+        assert_eq!(bitcask.keys_since(0).count(), 4);
+        assert_eq!(bitcask.keys_since(clock.now()).count(), 0);
+    }
+
+    #[test]
+    fn test_duplicate_file_ids() {
+        let path = fresh_dir("/tmp/test105");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        bitcask.put(b"a".to_vec(), b"one".to_vec()).unwrap();
+        bitcask.put(b"b".to_vec(), b"two".to_vec()).unwrap();
+        let file_id = bitcask.active_file_id;
+        bitcask.close().expect("Failed to close Bitcask");
+        // Parses to the same number, but isn't a name the store writes.
+        let stray = Path::new(path).join(format!("0{}.dat", file_id));
+        fs::copy(Path::new(path).join(format!("{}.dat", file_id)), &stray).unwrap();
+        assert_eq!(get_file_id(&stray), None);
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.get(b"a").unwrap(), b"one");
+        assert_eq!(bitcask.get(b"b").unwrap(), b"two");
+        bitcask.merge().expect("Failed to merge");
+        assert!(stray.exists());
+        drop(bitcask);
+
+        // The same name in two shard directories is refused outright.
+        let path = fresh_dir("/tmp/test105-sharded");
+        let options = Options::builder().shards(2).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        bitcask.put(b"a".to_vec(), b"one".to_vec()).unwrap();
+        let file_id = bitcask.active_file_id;
+        let filepath = bitcask.layout.path(&bitcask.data_path, file_id, "dat");
+        bitcask.close().expect("Failed to close Bitcask");
+        let other_shard = format!("{:03}", (file_id + 1) % 2);
+        fs::copy(
+            &filepath,
+            Path::new(path)
+                .join(other_shard)
+                .join(filepath.file_name().unwrap()),
+        )
+        .unwrap();
+        assert!(matches!(
+            Bitcask::open_with_options(path, options),
+            Err(BitcaskError::DuplicateFileId(id)) if id == file_id
+        ));
+    }
+
+    #[test]
+    fn test_failed_auto_merge_keeps_write() {
+        let path = fresh_dir("/tmp/test106");
+        let options = Options::builder().auto_merge_threshold(0.3).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        // Every merge fails to delete this.
+        fs::create_dir(Path::new(path).join("1.hint")).unwrap();
+        for i in 0..10u8 {
+            bitcask
+                .put(b"key".to_vec(), vec![i; 20])
+                .expect("Failed to put value");
+            assert_eq!(bitcask.get(b"key").unwrap(), vec![i; 20]);
+        }
+        assert!(bitcask.delete(b"key").expect("Failed to delete key"));
+        assert!(!bitcask.contains_key(b"key"));
+        assert!(bitcask.stats().unwrap().failed_auto_merges > 0);
+        assert!(matches!(
+            bitcask.take_auto_merge_error(),
+            Some(BitcaskError::Io(_))
+        ));
+        assert!(bitcask.take_auto_merge_error().is_none());
+    }
+
+    #[test]
+    fn test_keep_versions_with_auto_merge() {
+        let path = fresh_dir("/tmp/test107");
+        let options = Options::builder()
+            .keep_versions(3)
+            .auto_merge_threshold(0.5)
+            .build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        for round in 0..3u8 {
+            for i in 0..10u8 {
+                bitcask.put(vec![i], vec![round; 50]).unwrap();
+            }
+        }
+        bitcask.merge().expect("Failed to merge");
+        // Everything left is a version the merge keeps.
+        assert_eq!(bitcask.dead_ratio(), 0.0);
+        assert_eq!(bitcask.stats().unwrap().reclaimable_bytes, 0);
+
+        let merges = bitcask.metrics().merges;
+        for i in 10..30u8 {
+            bitcask.put(vec![i], vec![i; 50]).unwrap();
+        }
+        assert_eq!(bitcask.metrics().merges, merges);
+        assert!(bitcask.dead_ratio() < 0.5);
+        for i in 0..10u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), vec![2; 50]);
+        }
+    }
+
+    #[test]
+    fn test_huge_ttl() {
+        let path = fresh_dir("/tmp/test108");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        for (key, ttl) in [
+            (b"max", Duration::MAX),
+            (b"u64", Duration::from_millis(u64::MAX)),
+        ] {
+            bitcask
+                .put_with_ttl(key.to_vec(), b"forever".to_vec(), ttl)
+                .expect("Failed to put value");
+            assert_eq!(bitcask.key_dir[key.as_slice()].expiry, u64::MAX);
+            assert_eq!(bitcask.get(key).unwrap(), b"forever");
+        }
+        bitcask.close().expect("Failed to close Bitcask");
+        let bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), 2);
+    }
+
+    #[test]
+    fn test_open_read_only_with_options() {
+        let path = fresh_dir("/tmp/test109");
+        let options = Options::builder()
+            .shards(4)
+            .encryption(EncryptionConfig::new([9; 32]))
+            .record_alignment(64)
+            .build();
+        let mut writer =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        for i in 0..8u8 {
+            writer.put(vec![i], vec![i; 10]).unwrap();
+        }
+        writer.rotate().unwrap();
+        writer.put(vec![8], vec![8; 10]).unwrap();
+        writer.sync().unwrap();
+
+        let reader =
+            Bitcask::open_read_only_with_options(path, options).expect("Failed to open Bitcask");
+        assert_eq!(reader.len(), 9);
+        for i in 0..9u8 {
+            assert_eq!(reader.get(&[i]).unwrap(), vec![i; 10]);
+        }
+        // Without the options the files aren't even found.
+        let blind = Bitcask::open_read_only(path).expect("Failed to open Bitcask");
+        assert!(blind.is_empty());
+    }
+
+    #[test]
+    fn test_read_only_option() {
+        let path = fresh_dir("/tmp/test110");
+        let options = Options::builder().shards(2).build();
+        let mut writer =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        writer.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        writer.sync().unwrap();
+
+        let options = Options {
+            read_only: true,
+            ..options
+        };
+        let mut reader = Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert_eq!(reader.get(b"key").unwrap(), b"value");
+        assert!(matches!(
+            reader.put(b"key".to_vec(), b"other".to_vec()),
+            Err(BitcaskError::ReadOnly)
+        ));
+        writer.put(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+        writer.sync().unwrap();
+        reader.reload().expect("Failed to reload");
+        assert_eq!(reader.get(b"key2").unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_failed_clear_leaves_handle_usable() {
+        let path = fresh_dir("/tmp/test111");
+        let options = Options::builder().max_file_size(100).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options.clone()).expect("Failed to open Bitcask");
+        for i in 0..10u8 {
+            bitcask.put(vec![i], vec![i; 20]).unwrap();
+        }
+        // A store file that can't be deleted.
+        let stuck = Path::new(path).join("1.hint");
+        fs::create_dir(&stuck).unwrap();
+        assert!(matches!(bitcask.clear(), Err(BitcaskError::Io(_))));
+
+        // Whichever keys survived still read back; the rest are gone.
+        let survivors: Vec<u8> = (0..10u8).filter(|i| bitcask.contains_key(&[*i])).collect();
+        for i in &survivors {
+            assert_eq!(bitcask.get(&[*i]).unwrap(), vec![*i; 20]);
+        }
+        bitcask.put(b"new".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(bitcask.get(b"new").unwrap(), b"value");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        fs::remove_dir(&stuck).unwrap();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        assert_eq!(bitcask.len(), survivors.len() + 1);
+        bitcask.clear().expect("Failed to clear");
+        assert!(bitcask.is_empty());
+    }
+
+    #[test]
+    fn test_list_keys_skips_deleted_and_expired() {
+        let path = fresh_dir("/tmp/test112");
+        let clock = MockClock::new(5_000_000);
+        let options = Options::builder().clock(clock.clone()).build();
+        let mut bitcask =
+            Bitcask::open_with_options(path, options).expect("Failed to open Bitcask");
+        bitcask
+            .put(b"kept".to_vec(), b"value".to_vec())
+            .expect("Failed to put value");
+        bitcask
+            .put(b"deleted".to_vec(), b"value".to_vec())
+            .expect("Failed to put value");
+        bitcask.delete(b"deleted").expect("Failed to delete key");
+        bitcask
+            .put_with_ttl(
+                b"expired".to_vec(),
+                b"value".to_vec(),
+                Duration::from_secs(1),
+            )
+            .expect("Failed to put value");
+        assert_eq!(bitcask.list_keys().unwrap().len(), 2);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(bitcask.list_keys(), Some(vec![&b"kept".to_vec()]));
+    }
 }
@@ -0,0 +1,78 @@
+//! Where the store gets the time. Record timestamps, TTL expiry and the
+//! seeds of new file ids all come from a `Clock`, so tests can swap the
+//! wall clock for one they move by hand.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Milliseconds since the epoch.
+    fn now(&self) -> u64;
+}
+
+/// The system's wall clock, which every store uses unless told otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64
+    }
+}
+
+/// A clock that stands still until it's set or advanced. Clones share one
+/// time, so a test can keep a clone to move the clock a store was opened
+/// with.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new(millis: u64) -> Self {
+        MockClock {
+            millis: Arc::new(AtomicU64::new(millis)),
+        }
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.millis
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1_000);
+        let shared = clock.clone();
+        assert_eq!(clock.now(), 1_000);
+        shared.advance(Duration::from_secs(2));
+        assert_eq!(clock.now(), 3_000);
+        shared.set(10);
+        assert_eq!(clock.now(), 10);
+        assert!(SystemClock.now() > 1_600_000_000_000);
+    }
+}
@@ -0,0 +1,344 @@
+//! Value compression. Both codecs are implemented here rather than pulled in
+//! as dependencies: LZ4 in its block format and Snappy in its raw format.
+
+/// Codec applied to values as they are written. Every record remembers how it
+/// was compressed, so this can change between opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Snappy,
+}
+
+const MIN_MATCH: usize = 4;
+const HASH_LOG: u32 = 12;
+const MAX_OFFSET: usize = 0xFFFF;
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap())
+}
+
+// Greedy match finder shared by both codecs. Calls `emit(literals_start,
+// match_pos, offset, match_len)` for every match found among the positions
+// before `match_limit`, never letting a match run past `end_limit`, and
+// returns where the trailing literals start.
+fn find_matches<F: FnMut(usize, usize, usize, usize)>(
+    input: &[u8],
+    match_limit: usize,
+    end_limit: usize,
+    mut emit: F,
+) -> usize {
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos < match_limit {
+        let seq = read_u32(input, pos);
+        let h = hash(seq);
+        // Slots hold position + 1 so that zero means empty.
+        let candidate = table[h];
+        table[h] = pos + 1;
+        if candidate > 0 {
+            let candidate = candidate - 1;
+            if pos - candidate <= MAX_OFFSET && read_u32(input, candidate) == seq {
+                let mut len = MIN_MATCH;
+                while pos + len < end_limit && input[candidate + len] == input[pos + len] {
+                    len += 1;
+                }
+                emit(anchor, pos, pos - candidate, len);
+                pos += len;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    anchor
+}
+
+// Repeats the `len` bytes found `offset` back from the end of `out`. The
+// ranges may overlap, which is how runs are encoded.
+fn copy_back(out: &mut Vec<u8>, offset: usize, len: usize) -> Option<()> {
+    if offset == 0 || offset > out.len() {
+        return None;
+    }
+    let start = out.len() - offset;
+    for i in 0..len {
+        let byte = out[start + i];
+        out.push(byte);
+    }
+    Some(())
+}
+
+pub(crate) fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut anchor = 0;
+    // The block format wants the last 5 bytes to be literals and no match to
+    // start within the last 12.
+    if input.len() > 12 {
+        anchor = find_matches(
+            input,
+            input.len() - 12,
+            input.len() - 5,
+            |start, pos, offset, len| {
+                lz4_sequence(&mut out, &input[start..pos], Some((offset, len)));
+            },
+        );
+    }
+    lz4_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn lz4_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_code = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_code.min(15) as u8);
+    if literals.len() >= 15 {
+        lz4_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_code >= 15 {
+            lz4_length(out, match_code - 15);
+        }
+    }
+}
+
+fn lz4_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn read_lz4_length(input: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+pub(crate) fn lz4_decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 2);
+    let mut pos = 0;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += read_lz4_length(input, &mut pos)?;
+        }
+        out.extend_from_slice(input.get(pos..pos.checked_add(literal_len)?)?);
+        pos += literal_len;
+        // The last sequence is literals only.
+        if pos == input.len() {
+            return Some(out);
+        }
+
+        let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+        pos += 2;
+        let mut match_len = (token & 0x0F) as usize;
+        if match_len == 15 {
+            match_len += read_lz4_length(input, &mut pos)?;
+        }
+        copy_back(&mut out, offset, match_len + MIN_MATCH)?;
+    }
+}
+
+pub(crate) fn snappy_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + 10);
+    let mut len = input.len() as u64;
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+
+    let mut anchor = 0;
+    if input.len() >= MIN_MATCH {
+        anchor = find_matches(
+            input,
+            input.len() - MIN_MATCH + 1,
+            input.len(),
+            |start, pos, offset, len| {
+                snappy_literal(&mut out, &input[start..pos]);
+                snappy_copy(&mut out, offset, len);
+            },
+        );
+    }
+    snappy_literal(&mut out, &input[anchor..]);
+    out
+}
+
+fn snappy_literal(out: &mut Vec<u8>, literals: &[u8]) {
+    if literals.is_empty() {
+        return;
+    }
+    let n = literals.len() - 1;
+    if n < 60 {
+        out.push((n as u8) << 2);
+    } else {
+        let count = match n {
+            0..=0xFF => 1,
+            0x100..=0xFFFF => 2,
+            0x10000..=0xFF_FFFF => 3,
+            _ => 4,
+        };
+        out.push((59 + count as u8) << 2);
+        out.extend_from_slice(&(n as u32).to_le_bytes()[..count]);
+    }
+    out.extend_from_slice(literals);
+}
+
+fn snappy_copy(out: &mut Vec<u8>, offset: usize, mut len: usize) {
+    while len > 0 {
+        // The one-byte-offset form only covers short, near copies.
+        if (4..=11).contains(&len) && offset < 2048 {
+            out.push(0b01 | (((len - 4) as u8) << 2) | (((offset >> 8) as u8) << 5));
+            out.push(offset as u8);
+            return;
+        }
+        let chunk = len.min(64);
+        out.push(0b10 | (((chunk - 1) as u8) << 2));
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        len -= chunk;
+    }
+}
+
+pub(crate) fn snappy_decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    let mut expected_len = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *input.get(pos)?;
+        pos += 1;
+        expected_len |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 63 {
+            return None;
+        }
+    }
+
+    let mut out = Vec::with_capacity(input.len() * 2);
+    while pos < input.len() {
+        let tag = input[pos];
+        pos += 1;
+        match tag & 0b11 {
+            0b00 => {
+                let mut len = (tag >> 2) as usize;
+                if len >= 60 {
+                    let count = len - 59;
+                    let bytes = input.get(pos..pos + count)?;
+                    len = bytes
+                        .iter()
+                        .rev()
+                        .fold(0, |acc, byte| (acc << 8) | *byte as usize);
+                    pos += count;
+                }
+                len += 1;
+                out.extend_from_slice(input.get(pos..pos.checked_add(len)?)?);
+                pos += len;
+            }
+            0b01 => {
+                let len = ((tag >> 2) & 0b111) as usize + 4;
+                let offset = (((tag >> 5) as usize) << 8) | *input.get(pos)? as usize;
+                pos += 1;
+                copy_back(&mut out, offset, len)?;
+            }
+            0b10 => {
+                let len = (tag >> 2) as usize + 1;
+                let offset = u16::from_le_bytes(input.get(pos..pos + 2)?.try_into().unwrap());
+                pos += 2;
+                copy_back(&mut out, offset as usize, len)?;
+            }
+            _ => {
+                let len = (tag >> 2) as usize + 1;
+                let offset = u32::from_le_bytes(input.get(pos..pos + 4)?.try_into().unwrap());
+                pos += 4;
+                copy_back(&mut out, offset as usize, len)?;
+            }
+        }
+    }
+    (out.len() as u64 == expected_len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<Vec<u8>> {
+        let mut seed = 0x2545F491u32;
+        let mut noise = Vec::new();
+        for _ in 0..5000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            noise.push(seed as u8);
+        }
+        let json = br#"{"id": 1, "name": "kuro", "tags": ["a", "b"]}"#.repeat(200);
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabc".to_vec(),
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+            vec![0u8; 100_000],
+            json,
+            noise.clone(),
+            [noise.clone(), noise].concat(),
+        ]
+    }
+
+    #[test]
+    fn test_lz4_round_trip() {
+        for sample in samples() {
+            let compressed = lz4_compress(&sample);
+            assert_eq!(lz4_decompress(&compressed), Some(sample));
+        }
+        assert!(lz4_compress(&[0u8; 100_000]).len() < 1000);
+    }
+
+    #[test]
+    fn test_snappy_round_trip() {
+        for sample in samples() {
+            let compressed = snappy_compress(&sample);
+            assert_eq!(snappy_decompress(&compressed), Some(sample));
+        }
+        assert!(snappy_compress(&[0u8; 100_000]).len() < 5000);
+    }
+
+    #[test]
+    fn test_decompress_reference_blocks() {
+        // "a", then a 5 byte match at offset 1, then 5 literals.
+        let lz4 = [0x11, b'a', 0x01, 0x00, 0x50, b'a', b'a', b'a', b'a', b'a'];
+        assert_eq!(lz4_decompress(&lz4), Some(b"a".repeat(11)));
+
+        // Length 11, literal "a", a 5 byte copy at offset 1, 5 literals.
+        let snappy = [
+            0x0B, 0x00, b'a', 0x12, 0x01, 0x00, 0x10, b'a', b'a', b'a', b'a', b'a',
+        ];
+        assert_eq!(snappy_decompress(&snappy), Some(b"a".repeat(11)));
+    }
+
+    #[test]
+    fn test_decompress_garbage() {
+        assert_eq!(lz4_decompress(&[]), None);
+        assert_eq!(lz4_decompress(&[0x10]), None);
+        assert_eq!(lz4_decompress(&[0x04, 0x05, 0x00]), None);
+        assert_eq!(snappy_decompress(&[0x05, 0x12, 0x01, 0x00]), None);
+        assert_eq!(snappy_decompress(&[0x02, 0x00, b'a']), None);
+    }
+}
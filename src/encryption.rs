@@ -0,0 +1,131 @@
+//! Value encryption at rest with ChaCha20-Poly1305 (RFC 8439), from the
+//! `chacha20poly1305` crate.
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use std::{fmt, io};
+
+pub(crate) const NONCE_SIZE: usize = 12;
+pub(crate) const TAG_SIZE: usize = 16;
+
+/// Symmetric key used to encrypt values as they are written and decrypt them
+/// as they are read. Keys stay in plaintext so the key dir can index them.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    key: [u8; 32],
+}
+
+impl EncryptionConfig {
+    pub fn new(key: [u8; 32]) -> Self {
+        EncryptionConfig { key }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.key.into())
+    }
+
+    // Encrypts `plaintext` under a fresh nonce, returning the nonce, the
+    // ciphertext and the tag, in that order. `aad` is authenticated but not
+    // stored.
+    pub(crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(self.seal_with_nonce(fresh_nonce()?, aad, plaintext))
+    }
+
+    fn seal_with_nonce(&self, nonce: [u8; NONCE_SIZE], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        // Only fails for plaintexts far past anything a record can hold.
+        let sealed = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("plaintext too long to encrypt");
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    // Reverses `seal`, or returns `None` if the key, `aad` or the bytes
+    // don't match what was sealed.
+    pub(crate) fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let payload = Payload { msg: rest, aad };
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .ok()
+    }
+}
+
+// The key never shows up in logs.
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig").finish_non_exhaustive()
+    }
+}
+
+// Nonces only have to be unique per key, and 96 random bits from the OS's
+// generator make a repeat vanishingly unlikely.
+fn fresh_nonce() -> io::Result<[u8; NONCE_SIZE]> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce)?;
+    Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rfc8439_vector() {
+        // Section 2.8.2. Records already on disk depend on the layout `seal`
+        // writes staying the same.
+        let key: [u8; 32] = (0x80..0xa0).collect::<Vec<u8>>().try_into().unwrap();
+        let config = EncryptionConfig::new(key);
+        let nonce: [u8; NONCE_SIZE] = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let sealed = config.seal_with_nonce(nonce, &aad, plaintext);
+        assert_eq!(sealed[..NONCE_SIZE], nonce);
+        let ciphertext = &sealed[NONCE_SIZE..sealed.len() - TAG_SIZE];
+        assert_eq!(
+            ciphertext[..16].to_vec(),
+            unhex("d31a8d34648e60db7b86afbc53ef7ec2")
+        );
+        assert_eq!(ciphertext[ciphertext.len() - 2..].to_vec(), unhex("6116"));
+        assert_eq!(
+            sealed[sealed.len() - TAG_SIZE..].to_vec(),
+            unhex("1ae10b594f09e26a7e902ecbd0600691")
+        );
+        assert_eq!(config.open(&aad, &sealed), Some(plaintext.to_vec()));
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let config = EncryptionConfig::new([7; 32]);
+        let sealed = config.seal(b"key", b"secret").unwrap();
+        assert_eq!(sealed.len(), NONCE_SIZE + 6 + TAG_SIZE);
+        assert_eq!(config.open(b"key", &sealed), Some(b"secret".to_vec()));
+        assert_ne!(
+            config.seal(b"key", b"secret").unwrap()[..NONCE_SIZE],
+            sealed[..NONCE_SIZE]
+        );
+
+        assert_eq!(EncryptionConfig::new([8; 32]).open(b"key", &sealed), None);
+        assert_eq!(config.open(b"other", &sealed), None);
+        let mut tampered = sealed.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert_eq!(config.open(b"key", &tampered), None);
+        assert_eq!(config.open(b"key", &sealed[..TAG_SIZE]), None);
+    }
+}
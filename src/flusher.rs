@@ -0,0 +1,127 @@
+//! The background thread behind `SyncPolicy::Interval`. It fsyncs the active
+//! file on a timer, so writes never wait on the disk themselves.
+
+use std::{
+    fs, io,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[derive(Debug)]
+struct State {
+    file: Arc<fs::File>,
+    stop: bool,
+    // The first sync that failed, kept until a writer picks it up.
+    error: Option<io::Error>,
+    syncs: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+/// Fsyncs a file every interval until dropped. Dropping it stops and joins
+/// the thread without waiting out the interval.
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub(crate) fn spawn(file: Arc<fs::File>, interval: Duration) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                file,
+                stop: false,
+                error: None,
+                syncs: 0,
+            }),
+            wake: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("kuro-flusher".to_string())
+            .spawn(move || run(&thread_shared, interval))?;
+        Ok(Flusher {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Moves the flusher on to `file`, the new active file after a rotation.
+    pub(crate) fn set_file(&self, file: Arc<fs::File>) {
+        self.shared.state.lock().unwrap().file = file;
+    }
+
+    /// Returns the error of a sync that failed since the last call, if any.
+    pub(crate) fn take_error(&self) -> Option<io::Error> {
+        self.shared.state.lock().unwrap().error.take()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn syncs(&self) -> u64 {
+        self.shared.state.lock().unwrap().syncs
+    }
+}
+
+fn run(shared: &Shared, interval: Duration) {
+    let mut state = shared.state.lock().unwrap();
+    // Checked before every wait too, in case the stop came in while the
+    // lock was let go.
+    while !state.stop {
+        state = shared.wake.wait_timeout(state, interval).unwrap().0;
+        if state.stop {
+            break;
+        }
+        // The writer carries on while the disk catches up.
+        let file = Arc::clone(&state.file);
+        drop(state);
+        let result = file.sync_data();
+        state = shared.state.lock().unwrap();
+        match result {
+            Ok(()) => state.syncs += 1,
+            Err(e) => {
+                state.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_drop_joins_thread() {
+        let path = "/tmp/flusher1";
+        let file = Arc::new(fs::File::create(path).unwrap());
+        let flusher = Flusher::spawn(Arc::clone(&file), Duration::from_millis(5)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(flusher.syncs() > 0);
+        assert!(flusher.take_error().is_none());
+
+        let shared = Arc::downgrade(&flusher.shared);
+        let started = Instant::now();
+        let idle = Flusher::spawn(file, Duration::from_secs(3600)).unwrap();
+        drop(idle);
+        drop(flusher);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // The thread's handle on the shared state went with it.
+        assert!(shared.upgrade().is_none());
+    }
+}
@@ -1 +1,11 @@
+#[cfg(feature = "tokio")]
+pub mod async_bitcask;
 pub mod bitcask;
+pub mod clock;
+pub mod compression;
+pub mod encryption;
+mod flusher;
+pub mod namespace;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod typed;
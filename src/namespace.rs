@@ -0,0 +1,119 @@
+//! Logical partitions of one store. A namespace prefixes every key with its
+//! name and a zero byte on the way in and strips it on the way out, so any
+//! number of them can share a directory without their keys colliding.
+
+use crate::bitcask::{Bitcask, BitcaskError};
+
+const SEPARATOR: u8 = 0x00;
+
+/// A view of a `Bitcask` that only sees the keys of one namespace. It
+/// borrows the store, so any number of namespaces can be used in turn over
+/// one open handle.
+#[derive(Debug)]
+pub struct Namespace<'a> {
+    inner: &'a mut Bitcask,
+    prefix: Vec<u8>,
+}
+
+impl Bitcask {
+    /// Views this store as the namespace `ns`.
+    pub fn namespace(&mut self, ns: &[u8]) -> Namespace<'_> {
+        Namespace::new(self, ns)
+    }
+}
+
+impl<'a> Namespace<'a> {
+    /// Views `inner` as the namespace `ns`. A name holding a zero byte could
+    /// overlap another namespace's keys, so names shouldn't contain one.
+    pub fn new(inner: &'a mut Bitcask, ns: &[u8]) -> Self {
+        let mut prefix = ns.to_vec();
+        prefix.push(SEPARATOR);
+        Namespace { inner, prefix }
+    }
+
+    fn full_key(&self, key: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), key].concat()
+    }
+
+    /// Writes `value` under `key`, returning the value it replaced, if any.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, BitcaskError> {
+        let key = self.full_key(&key);
+        self.inner.put(key, value)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, BitcaskError> {
+        self.inner.get(&self.full_key(key))
+    }
+
+    /// Deletes `key`, returning whether it had a live value.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, BitcaskError> {
+        let key = self.full_key(key);
+        self.inner.delete(&key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.inner.contains_key(&self.full_key(key))
+    }
+
+    /// The live keys of this namespace, without the prefix.
+    pub fn list_keys(&self) -> Vec<Vec<u8>> {
+        self.prefix_scan(&[]).collect()
+    }
+
+    /// The live keys of this namespace that start with `prefix`, without
+    /// the namespace prefix.
+    pub fn prefix_scan<'b>(&'b self, prefix: &'b [u8]) -> impl Iterator<Item = Vec<u8>> + 'b {
+        self.inner
+            .prefix_scan(&self.prefix)
+            .map(|key| &key[self.prefix.len()..])
+            .filter(move |key| key.starts_with(prefix))
+            .map(<[u8]>::to_vec)
+    }
+
+    /// The underlying store, for everything the namespace doesn't cover.
+    /// Keys there carry the namespace prefix.
+    pub fn inner(&mut self) -> &mut Bitcask {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::fresh_dir;
+
+    #[test]
+    fn test_namespaces() {
+        let path = fresh_dir("/tmp/namespace1");
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let mut users = bitcask.namespace(b"users");
+        users
+            .put(b"a".to_vec(), b"ada".to_vec())
+            .expect("Failed to put value");
+        users
+            .put(b"b".to_vec(), b"bob".to_vec())
+            .expect("Failed to put value");
+
+        let mut orders = bitcask.namespace(b"orders");
+        assert!(!orders.contains_key(b"a"));
+        orders
+            .put(b"a".to_vec(), b"order-1".to_vec())
+            .expect("Failed to put value");
+        assert_eq!(orders.list_keys(), vec![b"a".to_vec()]);
+        assert_eq!(orders.get(b"a").unwrap(), b"order-1");
+        assert_eq!(bitcask.namespace(b"users").get(b"a").unwrap(), b"ada");
+        bitcask.close().expect("Failed to close Bitcask");
+
+        let mut bitcask = Bitcask::open(path).expect("Failed to open Bitcask");
+        let users = bitcask.namespace(b"users");
+        assert_eq!(users.get(b"a").unwrap(), b"ada");
+        let mut keys = users.list_keys();
+        keys.sort();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(
+            users.prefix_scan(b"b").collect::<Vec<_>>(),
+            vec![b"b".to_vec()]
+        );
+        assert_eq!(bitcask.len(), 3);
+    }
+}
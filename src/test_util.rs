@@ -0,0 +1,10 @@
+//! Fixtures shared by the test modules.
+
+use std::fs;
+
+// Each test gets its own directory, wiped before use, so tests don't depend
+// on each other's leftovers or run order.
+pub(crate) fn fresh_dir(path: &str) -> &str {
+    let _ = fs::remove_dir_all(path);
+    path
+}